@@ -1,12 +1,20 @@
-use core::{alloc::GlobalAlloc, ptr::null_mut};
+use core::{alloc::{GlobalAlloc, Layout}, ptr::{self, null_mut}};
 use linked_list_allocator::align_up;
-use crate::allocator::Locked;
+use crate::allocator::{GrowFn, HeapStats, KernelHeap, Locked, Usage, grow_size};
 
+/// Hands out memory by moving a pointer forward.
+///
+/// Only the most recent allocation can be freed or resized in place, everything else is
+/// given back at once when the last allocation is freed or when the allocator is `reset`
+/// to an earlier `mark`, which makes it usable as a boot-time or per-request arena.
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     allocations: usize,
     next: usize,
+    max_heap_size: usize,
+    grow: Option<GrowFn>,
+    usage: Usage,
 }
 
 impl BumpAllocator {
@@ -17,6 +25,9 @@ impl BumpAllocator {
             heap_end: 0,
             allocations: 0,
             next: 0,
+            max_heap_size: 0,
+            grow: None,
+            usage: Usage::new(),
         }
     }
     /// Initializes the bump allocator with the given heap bounds.
@@ -28,10 +39,100 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Remembers the current position, see `reset`
+    pub fn mark(&self) -> BumpMark {
+        BumpMark {
+            next: self.next,
+            allocations: self.allocations,
+            usage: self.usage,
+        }
+    }
+
+    /// Frees everything allocated since `mark` was taken at once. The high water mark keeps
+    /// the peak reached meanwhile.
+    ///
+    /// # Safety
+    ///
+    /// No allocation made after the mark may be used or freed afterwards, and the mark must
+    /// have been taken from this allocator after the last reset to an earlier mark.
+    pub unsafe fn reset(&mut self, mark: BumpMark) {
+        self.next = mark.next;
+        self.allocations = mark.allocations;
+        self.usage.bytes_in_use = mark.usage.bytes_in_use;
+        self.usage.allocations = mark.usage.allocations;
+    }
+
+    /// maps more memory until the heap reaches `end`, returns false if it couldn't
+    fn grow_to(&mut self, end: usize) -> bool {
+        let Some(grow) = self.grow else {
+            return false;
+        };
+
+        while self.heap_end < end {
+            let heap_size = self.heap_end - self.heap_start;
+            let size = grow_size(end - self.heap_end, heap_size, self.max_heap_size);
+            if size == 0 {
+                return false;
+            }
+
+            let mapped = grow(self.heap_end, size);
+            if mapped == 0 {
+                return false;
+            }
+            self.heap_end += mapped;
+        }
+        true
+    }
+}
+
+/// A position of a `BumpAllocator`, taken by `mark`
+#[derive(Debug, Clone, Copy)]
+pub struct BumpMark {
+    next: usize,
+    allocations: usize,
+    usage: Usage,
+}
+
+impl Locked<BumpAllocator> {
+    /// Runs `f` and frees everything that was allocated from this allocator meanwhile.
+    ///
+    /// # Safety
+    ///
+    /// Nothing allocated from this allocator inside `f` may be used after it returns.
+    pub unsafe fn scope<R>(&self, f: impl FnOnce(&Self) -> R) -> R {
+        let mark = self.lock().mark();
+        let result = f(self);
+        unsafe { self.lock().reset(mark) };
+        result
+    }
+}
+
+impl KernelHeap for BumpAllocator {
+    unsafe fn init_heap(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.init(heap_start, heap_size) };
+    }
+
+    fn set_growth(&mut self, max_heap_size: usize, grow: GrowFn) {
+        self.max_heap_size = max_heap_size;
+        self.grow = Some(grow);
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.usage.bytes_in_use,
+            high_water_mark: self.usage.high_water_mark,
+            allocations: self.usage.allocations,
+            free_blocks: Default::default(),
+            heap_size: self.heap_end - self.heap_start,
+            fallback_free: self.heap_end - self.next,
+            largest_free_hole: self.heap_end - self.next,
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock();
 
         // align cuz uhh, cpu doesnt like stuff that isnt neat
@@ -42,24 +143,118 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         };
 
         // check if the allocation ending overflows the heap ending
-        if alloc_end > bump.heap_end {
+        if alloc_end > bump.heap_end && !bump.grow_to(alloc_end) {
             null_mut()
         } else {
             bump.next = alloc_start + layout.size();
             bump.allocations += 1;
+            bump.usage.record_alloc(layout.size());
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock();
+        bump.usage.record_dealloc(layout.size());
 
-        // if ptr == bump.next as *mut u8 {
-        //     bump.next = (ptr as usize).wrapping_sub(layout.size());
-        // }
+        // the most recent allocation can be given back right away
+        if ptr as usize + layout.size() == bump.next {
+            bump.next = ptr as usize;
+        }
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        {
+            let mut bump = self.lock();
+            let start = ptr as usize;
+            let Some(new_end) = start.checked_add(new_size) else {
+                return null_mut();
+            };
+
+            if start + layout.size() == bump.next {
+                // the most recent allocation just moves the end
+                if new_end <= bump.heap_end || bump.grow_to(new_end) {
+                    bump.next = new_end;
+                    bump.usage.record_realloc(layout.size(), new_size);
+                    return ptr;
+                }
+            } else if new_size <= layout.size() {
+                // shrinking in place always works, the tail stays unused until the next reset
+                bump.usage.record_realloc(layout.size(), new_size);
+                return ptr;
+            }
+        }
+
+        // same as the default implementation, without holding the lock
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+fn test_arena(buffer: &mut [u8]) -> Locked<BumpAllocator> {
+    let arena = Locked::new(BumpAllocator::new());
+    unsafe { arena.lock().init(buffer.as_mut_ptr() as usize, buffer.len()) };
+    arena
+}
+
+#[test_case]
+fn bump_frees_last_allocation() {
+    let mut buffer = [0u8; 1024];
+    let arena = test_arena(&mut buffer);
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let _a = arena.alloc(layout);
+        let b = arena.alloc(layout);
+        arena.dealloc(b, layout);
+        assert_eq!(arena.alloc(layout), b);
+    }
+}
+
+#[test_case]
+fn bump_resizes_last_allocation_in_place() {
+    let mut buffer = [0u8; 1024];
+    let arena = test_arena(&mut buffer);
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let a = arena.alloc(layout);
+        assert_eq!(arena.realloc(a, layout, 256), a);
+        let b = arena.alloc(layout);
+        assert_eq!(b as usize, a as usize + 256);
+
+        // a is not the last one anymore, so growing it has to move it
+        let big = Layout::from_size_align(256, 8).unwrap();
+        assert_ne!(arena.realloc(a, big, 512), a);
+    }
+}
+
+#[test_case]
+fn bump_scope_resets() {
+    let mut buffer = [0u8; 1024];
+    let arena = test_arena(&mut buffer);
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let _outer = arena.alloc(layout);
+        let first = arena.scope(|arena| {
+            let first = arena.alloc(layout);
+            arena.alloc(layout);
+            first
+        });
+        assert_eq!(arena.alloc(layout), first);
+        let stats = arena.lock().stats();
+        assert_eq!(stats.allocations, 2);
+        // the scope had three allocations live at once
+        assert!(stats.high_water_mark >= 3 * 8);
+    }
 }
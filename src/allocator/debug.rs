@@ -0,0 +1,148 @@
+use core::{alloc::{GlobalAlloc, Layout}, fmt, ptr::null_mut};
+use linked_list_allocator::align_up;
+
+use crate::backtrace::return_addresses;
+
+/// guard bytes on each side of every allocation
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+/// fresh allocations are filled with this, to make reads of uninitialized memory stand out
+const UNINIT_BYTE: u8 = 0xcd;
+/// freed allocations are filled with this
+const POISON_BYTE: u8 = 0xdd;
+
+const ALIVE: usize = 0xa11c_a7ed;
+const FREED: usize = 0xdead_f4ee;
+
+/// how many return addresses are recorded per allocation
+const TRACE_DEPTH: usize = 4;
+/// frames belonging to the allocator itself that are left out of traces
+const TRACE_SKIP: usize = 1;
+
+/// Bookkeeping stored in front of the leading redzone of every allocation
+#[repr(C)]
+struct Header {
+    /// the inner allocators keep their free list nodes at the start of a block, so nothing
+    /// that has to survive a free may live here
+    _free_list: [usize; 2],
+    state: usize,
+    size: usize,
+    align: usize,
+    alloc_trace: [usize; TRACE_DEPTH],
+}
+
+/// A checking layer around another allocator, enabled by the `debug_heap` feature.
+///
+/// Every allocation gets a header and a redzone of guard bytes on each side, freed memory is
+/// poisoned. Double frees, frees with the wrong `Layout` and writes outside of a block panic
+/// with the offending layout and the return addresses of the allocation and the free.
+pub struct DebugAllocator<A> {
+    inner: A,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// offset of the user pointer from the start of the inner block
+fn user_offset(layout: &Layout) -> usize {
+    align_up(size_of::<Header>() + REDZONE_SIZE, layout.align().max(align_of::<Header>()))
+}
+
+fn inner_layout(layout: &Layout) -> Option<Layout> {
+    let size = user_offset(layout)
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, layout.align().max(align_of::<Header>())).ok()
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    unsafe { ptr.sub(REDZONE_SIZE + size_of::<Header>()) as *mut Header }
+}
+
+/// return addresses printed as a list
+struct Trace([usize; TRACE_DEPTH]);
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for addr in self.0.iter().take_while(|&&addr| addr != 0) {
+            write!(f, " {addr:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(inner_layout) = inner_layout(&layout) else {
+            return null_mut();
+        };
+        let block = unsafe { self.inner.alloc(inner_layout) };
+        if block.is_null() {
+            return block;
+        }
+
+        unsafe {
+            let ptr = block.add(user_offset(&layout));
+            header(ptr).write(Header {
+                _free_list: [0; 2],
+                state: ALIVE,
+                size: layout.size(),
+                align: layout.align(),
+                alloc_trace: return_addresses(TRACE_SKIP),
+            });
+            ptr.sub(REDZONE_SIZE).write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+            ptr.write_bytes(UNINIT_BYTE, layout.size());
+            ptr.add(layout.size()).write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = unsafe { &mut *header(ptr) };
+        let free_trace = Trace(return_addresses(TRACE_SKIP));
+
+        match header.state {
+            ALIVE => {}
+            FREED => panic!(
+                "heap: double free of {:?} at {:p}\n  allocated at{}\n  freed at{}",
+                layout, ptr, Trace(header.alloc_trace), free_trace
+            ),
+            _ => panic!(
+                "heap: free of {:?} at {:p} which was not allocated (or its header got overwritten)\n  freed at{}",
+                layout, ptr, free_trace
+            ),
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap: free of {:p} with {:?}, but it was allocated with size {} align {}\n  allocated at{}\n  freed at{}",
+                ptr, layout, header.size, header.align, Trace(header.alloc_trace), free_trace
+            );
+        }
+
+        let front = unsafe { core::slice::from_raw_parts(ptr.sub(REDZONE_SIZE), REDZONE_SIZE) };
+        let back = unsafe { core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE) };
+        for (side, redzone) in [("before the start", front), ("past the end", back)] {
+            if let Some(offset) = redzone.iter().position(|&b| b != REDZONE_BYTE) {
+                panic!(
+                    "heap: write {} of {:?} at {:p} (redzone byte {} is {:#x})\n  allocated at{}\n  freed at{}",
+                    side, layout, ptr, offset, redzone[offset], Trace(header.alloc_trace), free_trace
+                );
+            }
+        }
+
+        header.state = FREED;
+        unsafe {
+            ptr.write_bytes(POISON_BYTE, layout.size());
+            let inner_layout = inner_layout(&layout).expect("layout was valid when allocated");
+            self.inner.dealloc(ptr.sub(user_offset(&layout)), inner_layout);
+        }
+    }
+}
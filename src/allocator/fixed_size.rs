@@ -1,42 +1,240 @@
 use core::{alloc::{GlobalAlloc, Layout}, ptr::{NonNull, null_mut}};
 
-use crate::allocator::Locked;
+use linked_list_allocator::align_up;
+
+use crate::allocator::{GrowFn, HeapStats, KernelHeap, Locked, PAGE_SIZE, Usage, linked_list::LinkedListAllocator};
 
 /// alignments must be power of 2 (binary)
 /// ranging from 16 to 2048
-const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
 
 #[repr(C)]
 struct ListNode {
     next: Option<*mut ListNode>,
 }
 
+/// Header at the start of every slab, a naturally aligned run of blocks of one size class
+/// taken from the fallback heap.
+#[repr(C)]
+struct Slab {
+    /// neighbours in the list of slabs of this class that have free blocks
+    next: Option<*mut Slab>,
+    prev: Option<*mut Slab>,
+    free_list: Option<*mut ListNode>,
+    free: usize,
+    capacity: usize,
+}
+
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<*mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap
+    /// slabs with at least one free block, per size class
+    list_heads: [Option<*mut Slab>; BLOCK_SIZES.len()],
+    /// completely free slabs kept around per size class, the rest goes back to the fallback heap
+    empty_slabs: [usize; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    usage: Usage,
 }
 
 unsafe impl Send for FixedSizeBlockAllocator {}
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
-        const EMPTY: Option<*mut ListNode> = None;
+        const EMPTY: Option<*mut Slab> = None;
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            empty_slabs: [0; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            free_blocks: [0; BLOCK_SIZES.len()],
+            usage: Usage::new(),
         }
     }
 
     /// this function is unsafe because the caller must guarantee that the given heap bounds are
-    /// valid, and the address is unused 
+    /// valid, and the address is unused
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size); }
     }
 
+    /// Gives every completely free slab back to the fallback heap, returns whether there was any
+    pub fn reclaim(&mut self) -> bool {
+        let mut reclaimed = false;
+        for size_idx in 0..BLOCK_SIZES.len() {
+            let mut current = self.list_heads[size_idx];
+            while let Some(slab) = current {
+                unsafe {
+                    current = (*slab).next;
+                    if (*slab).free == (*slab).capacity {
+                        self.release_slab(slab, size_idx);
+                        reclaimed = true;
+                    }
+                }
+            }
+            self.empty_slabs[size_idx] = 0;
+        }
+        reclaimed
+    }
+
     fn fallback_alloc(&mut self, layout: &Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(*layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => null_mut(),
+        let mut ptr = self.fallback_allocator.allocate_or_grow(*layout);
+        if ptr.is_none() && self.reclaim() {
+            // the heap is at its limit, try again with the memory that was sitting in empty slabs
+            ptr = self.fallback_allocator.allocate_first_fit(*layout);
+        }
+        match ptr {
+            Some(ptr) => ptr.as_ptr(),
+            None => null_mut(),
+        }
+    }
+
+    fn alloc_block(&mut self, size_idx: usize) -> *mut u8 {
+        let slab = match self.list_heads[size_idx] {
+            Some(slab) => slab,
+            None => match self.new_slab(size_idx) {
+                Some(slab) => slab,
+                None => return null_mut(),
+            },
+        };
+
+        unsafe {
+            if (*slab).free == (*slab).capacity {
+                self.empty_slabs[size_idx] -= 1;
+            }
+
+            let node = (*slab).free_list.expect("slab in free list without free blocks");
+            (*slab).free_list = (*node).next.take();
+            (*slab).free -= 1;
+            self.free_blocks[size_idx] -= 1;
+
+            if (*slab).free == 0 {
+                self.unlink(slab, size_idx);
+            }
+            node as *mut u8
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be a block of this size class handed out by `alloc_block`
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, size_idx: usize) {
+        let size = BLOCK_SIZES[size_idx];
+        assert!(size_of::<ListNode>() <= size);
+        assert!(align_of::<ListNode>() <= size);
+
+        // slabs are aligned to their size, so the header is found by rounding down
+        let slab = (ptr as usize & !(slab_size(size_idx) - 1)) as *mut Slab;
+        let ptr = ptr as *mut ListNode;
+        unsafe {
+            ptr.write(ListNode { next: (*slab).free_list });
+            (*slab).free_list = Some(ptr);
+            (*slab).free += 1;
+            self.free_blocks[size_idx] += 1;
+
+            if (*slab).free == 1 {
+                self.link(slab, size_idx);
+            }
+            if (*slab).free == (*slab).capacity {
+                // keep one empty slab so alternating alloc and free doesn't hit the fallback heap
+                if self.empty_slabs[size_idx] == 0 {
+                    self.empty_slabs[size_idx] += 1;
+                } else {
+                    self.release_slab(slab, size_idx);
+                }
+            }
+        }
+    }
+
+    /// carves a new slab out of the fallback heap and puts it in front of the class's list
+    fn new_slab(&mut self, size_idx: usize) -> Option<*mut Slab> {
+        let size = BLOCK_SIZES[size_idx];
+        let layout = slab_layout(size_idx);
+        let slab = self.fallback_alloc(&layout) as *mut Slab;
+        if slab.is_null() {
+            return None;
+        }
+
+        let first_block = align_up(size_of::<Slab>(), size);
+        let capacity = (layout.size() - first_block) / size;
+
+        // thread the free list through the blocks, lowest address first
+        let base = slab as usize;
+        let mut free_list = None;
+        for i in (0..capacity).rev() {
+            let node = (base + first_block + i * size) as *mut ListNode;
+            unsafe { node.write(ListNode { next: free_list }) };
+            free_list = Some(node);
+        }
+
+        unsafe {
+            slab.write(Slab {
+                next: None,
+                prev: None,
+                free_list,
+                free: capacity,
+                capacity,
+            });
+        }
+        self.free_blocks[size_idx] += capacity;
+        self.empty_slabs[size_idx] += 1;
+        self.link(slab, size_idx);
+        Some(slab)
+    }
+
+    /// # Safety
+    ///
+    /// `slab` must be completely free and in the list of its class
+    unsafe fn release_slab(&mut self, slab: *mut Slab, size_idx: usize) {
+        unsafe {
+            self.unlink(slab, size_idx);
+            self.free_blocks[size_idx] -= (*slab).capacity;
+            let ptr = NonNull::new_unchecked(slab as *mut u8);
+            self.fallback_allocator.deallocate(ptr, slab_layout(size_idx));
+        }
+    }
+
+    fn link(&mut self, slab: *mut Slab, size_idx: usize) {
+        let head = self.list_heads[size_idx];
+        unsafe {
+            (*slab).prev = None;
+            (*slab).next = head;
+            if let Some(head) = head {
+                (*head).prev = Some(slab);
+            }
+        }
+        self.list_heads[size_idx] = Some(slab);
+    }
+
+    fn unlink(&mut self, slab: *mut Slab, size_idx: usize) {
+        unsafe {
+            let (prev, next) = ((*slab).prev.take(), (*slab).next.take());
+            match prev {
+                Some(prev) => (*prev).next = next,
+                None => self.list_heads[size_idx] = next,
+            }
+            if let Some(next) = next {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+impl KernelHeap for FixedSizeBlockAllocator {
+    unsafe fn init_heap(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.init(heap_start, heap_size) };
+    }
+
+    fn set_growth(&mut self, max_heap_size: usize, grow: GrowFn) {
+        self.fallback_allocator.set_growth(max_heap_size, grow);
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.usage.bytes_in_use,
+            high_water_mark: self.usage.high_water_mark,
+            allocations: self.usage.allocations,
+            free_blocks: self.free_blocks,
+            heap_size: self.fallback_allocator.size(),
+            fallback_free: self.fallback_allocator.free(),
+            largest_free_hole: self.fallback_allocator.holes().map(|(_, size)| size).max().unwrap_or(0),
         }
     }
 }
@@ -46,43 +244,24 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock();
 
         // find suitable size
-        match list_index(&layout) {
-            Some(size_idx) => {
-                match allocator.list_heads[size_idx] {
-                    Some(node) => {
-                        unsafe { allocator.list_heads[size_idx] = (*node).next.take() };
-                        node as *mut u8
-                    }
-                    None => {
-                        let size = BLOCK_SIZES[size_idx];
-                        let layout = Layout::from_size_align(size, size).unwrap();
-                        allocator.fallback_alloc(&layout)
-                    },
-                }
-            }
+        let ptr = match list_index(&layout) {
+            Some(size_idx) => allocator.alloc_block(size_idx),
             None => allocator.fallback_alloc(&layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.usage.record_alloc(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.usage.record_dealloc(layout.size());
 
         // find suitable size
         match list_index(&layout) {
-            Some(size_idx) => {
-                let size = BLOCK_SIZES[size_idx];
-                let new_node = ListNode {
-                    next: allocator.list_heads[size_idx],
-                };
-                assert!(size_of::<ListNode>() <= size);
-                assert!(align_of::<ListNode>() <= size);
-
-                let ptr = ptr as *mut ListNode;
-                unsafe {
-                    ptr.write(new_node);
-                    allocator.list_heads[size_idx] = Some(ptr);
-                }
-            }
+            Some(size_idx) => unsafe { allocator.dealloc_block(ptr, size_idx) },
             None => {
                 let ptr = NonNull::new(ptr).expect("Null pointer");
                 unsafe { allocator.fallback_allocator.deallocate(ptr, layout) };
@@ -97,3 +276,13 @@ fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size) // return the suitable size
 }
+
+/// a page, or enough pages that the header costs at most one block in eight
+fn slab_size(size_idx: usize) -> usize {
+    (BLOCK_SIZES[size_idx] * 8).max(PAGE_SIZE)
+}
+
+fn slab_layout(size_idx: usize) -> Layout {
+    let size = slab_size(size_idx);
+    Layout::from_size_align(size, size).unwrap()
+}
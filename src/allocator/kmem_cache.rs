@@ -0,0 +1,298 @@
+use core::{fmt, marker::PhantomData, ops::{Deref, DerefMut}, ptr::NonNull};
+use linked_list_allocator::align_up;
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame}};
+
+use crate::{allocator::PAGE_SIZE, memory};
+
+#[repr(C)]
+struct FreeObject {
+    next: Option<*mut FreeObject>,
+}
+
+/// Header at the start of every page of a cache
+#[repr(C)]
+struct SlabPage {
+    next: Option<*mut SlabPage>,
+    prev: Option<*mut SlabPage>,
+    free_list: Option<*mut FreeObject>,
+    free: usize,
+}
+
+/// Numbers describing a single `KmemCache`
+#[derive(Debug, Clone, Copy)]
+pub struct KmemCacheStats {
+    pub name: &'static str,
+    /// bytes per object including padding
+    pub object_size: usize,
+    pub objects_per_page: usize,
+    pub pages: usize,
+    pub objects_in_use: usize,
+    /// the most objects that were ever in use at once
+    pub high_water_mark: usize,
+    /// number of allocations over the cache's lifetime
+    pub total_allocations: usize,
+}
+
+impl KmemCacheStats {
+    pub fn objects_free(&self) -> usize {
+        self.pages * self.objects_per_page - self.objects_in_use
+    }
+}
+
+impl fmt::Display for KmemCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} in use, {} free, {} pages of {} x {}B, high-water mark {}, {} allocations",
+            self.name, self.objects_in_use, self.objects_free(), self.pages, self.objects_per_page,
+            self.object_size, self.high_water_mark, self.total_allocations)
+    }
+}
+
+struct CacheInner {
+    /// pages with at least one free object
+    partial: Option<*mut SlabPage>,
+    empty_pages: usize,
+    pages: usize,
+    objects_in_use: usize,
+    high_water_mark: usize,
+    total_allocations: usize,
+}
+
+unsafe impl Send for CacheInner {}
+
+/// A cache of equally sized objects of type `T`, carved out of whole pages taken straight
+/// from the global frame allocator instead of going through the global allocator.
+///
+/// Objects live in the physical memory mapping, so `memory::init_global` has to run first.
+/// Every page keeps a small header in front of its objects, a page whose objects are all
+/// free goes back to the frame allocator unless it is the only empty one.
+pub struct KmemCache<T> {
+    name: &'static str,
+    constructor: Option<fn() -> T>,
+    inner: Mutex<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for KmemCache<T> {}
+unsafe impl<T: Send> Send for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            constructor: None,
+            inner: Mutex::new(CacheInner {
+                partial: None,
+                empty_pages: 0,
+                pages: 0,
+                objects_in_use: 0,
+                high_water_mark: 0,
+                total_allocations: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a cache whose `alloc_constructed` builds objects with `constructor`
+    pub const fn with_constructor(name: &'static str, constructor: fn() -> T) -> Self {
+        let mut cache = Self::new(name);
+        cache.constructor = Some(constructor);
+        cache
+    }
+
+    /// Moves `value` into an object from the cache, `None` if no frame is left
+    pub fn alloc(&self, value: T) -> Option<CacheBox<'_, T>> {
+        let ptr = self.alloc_object()?.cast::<T>();
+        unsafe { ptr.write(value) };
+        Some(CacheBox { ptr, cache: self })
+    }
+
+    /// Allocates an object built by the cache's constructor.
+    ///
+    /// Panics if the cache was created without one.
+    pub fn alloc_constructed(&self) -> Option<CacheBox<'_, T>> {
+        let constructor = self.constructor.expect("KmemCache has no constructor");
+        self.alloc(constructor())
+    }
+
+    pub fn stats(&self) -> KmemCacheStats {
+        let inner = self.inner.lock();
+        KmemCacheStats {
+            name: self.name,
+            object_size: object_size::<T>(),
+            objects_per_page: objects_per_page::<T>(),
+            pages: inner.pages,
+            objects_in_use: inner.objects_in_use,
+            high_water_mark: inner.high_water_mark,
+            total_allocations: inner.total_allocations,
+        }
+    }
+
+    fn alloc_object(&self) -> Option<NonNull<u8>> {
+        let mut inner = self.inner.lock();
+        let page = match inner.partial {
+            Some(page) => page,
+            None => self.grow(&mut inner)?,
+        };
+
+        unsafe {
+            if (*page).free == objects_per_page::<T>() {
+                inner.empty_pages -= 1;
+            }
+
+            let object = (*page).free_list.expect("page in partial list without free objects");
+            (*page).free_list = (*object).next.take();
+            (*page).free -= 1;
+            if (*page).free == 0 {
+                unlink(&mut inner.partial, page);
+            }
+
+            inner.objects_in_use += 1;
+            inner.high_water_mark = inner.high_water_mark.max(inner.objects_in_use);
+            inner.total_allocations += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be an object of this cache that is not used anymore
+    unsafe fn free_object(&self, ptr: NonNull<u8>) {
+        let mut inner = self.inner.lock();
+        let page = (ptr.as_ptr() as usize & !(PAGE_SIZE - 1)) as *mut SlabPage;
+        let object = ptr.as_ptr() as *mut FreeObject;
+
+        unsafe {
+            object.write(FreeObject { next: (*page).free_list });
+            (*page).free_list = Some(object);
+            (*page).free += 1;
+            inner.objects_in_use -= 1;
+
+            if (*page).free == 1 {
+                link(&mut inner.partial, page);
+            }
+            if (*page).free == objects_per_page::<T>() {
+                if inner.empty_pages == 0 {
+                    inner.empty_pages += 1;
+                } else {
+                    unlink(&mut inner.partial, page);
+                    inner.pages -= 1;
+                    release_page(page);
+                }
+            }
+        }
+    }
+
+    /// takes a new page from the frame allocator and threads the free list through it
+    fn grow(&self, inner: &mut CacheInner) -> Option<*mut SlabPage> {
+        let capacity = objects_per_page::<T>();
+        assert!(capacity > 0, "{}: objects don't fit in a page", self.name);
+
+        let frame = memory::with_memory(|_, frame_allocator| frame_allocator.allocate_frame())?;
+        let page: *mut SlabPage = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+
+        let base = page as usize + first_object::<T>();
+        let mut free_list = None;
+        for i in (0..capacity).rev() {
+            let object = (base + i * object_size::<T>()) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free_list }) };
+            free_list = Some(object);
+        }
+
+        unsafe {
+            page.write(SlabPage {
+                next: None,
+                prev: None,
+                free_list,
+                free: capacity,
+            });
+        }
+        inner.pages += 1;
+        inner.empty_pages += 1;
+        link(&mut inner.partial, page);
+        Some(page)
+    }
+}
+
+/// An object owned by a `KmemCache`, given back to it on drop
+pub struct CacheBox<'c, T> {
+    ptr: NonNull<T>,
+    cache: &'c KmemCache<T>,
+}
+
+impl<T> Deref for CacheBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.drop_in_place();
+            self.cache.free_object(self.ptr.cast());
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CacheBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<T: Send> Send for CacheBox<'_, T> {}
+unsafe impl<T: Sync> Sync for CacheBox<'_, T> {}
+
+fn object_align<T>() -> usize {
+    align_of::<T>().max(align_of::<FreeObject>())
+}
+
+fn object_size<T>() -> usize {
+    align_up(size_of::<T>().max(size_of::<FreeObject>()), object_align::<T>())
+}
+
+fn first_object<T>() -> usize {
+    align_up(size_of::<SlabPage>(), object_align::<T>())
+}
+
+fn objects_per_page<T>() -> usize {
+    PAGE_SIZE.saturating_sub(first_object::<T>()) / object_size::<T>()
+}
+
+fn link(head: &mut Option<*mut SlabPage>, page: *mut SlabPage) {
+    unsafe {
+        (*page).prev = None;
+        (*page).next = *head;
+        if let Some(old) = *head {
+            (*old).prev = Some(page);
+        }
+    }
+    *head = Some(page);
+}
+
+fn unlink(head: &mut Option<*mut SlabPage>, page: *mut SlabPage) {
+    unsafe {
+        let (prev, next) = ((*page).prev.take(), (*page).next.take());
+        match prev {
+            Some(prev) => (*prev).next = next,
+            None => *head = next,
+        }
+        if let Some(next) = next {
+            (*next).prev = prev;
+        }
+    }
+}
+
+fn release_page(page: *mut SlabPage) {
+    let frame = PhysFrame::containing_address(memory::virt_to_phys(VirtAddr::from_ptr(page)));
+    memory::with_memory(|_, frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+}
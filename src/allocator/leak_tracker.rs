@@ -0,0 +1,193 @@
+use core::{alloc::{GlobalAlloc, Layout}, fmt};
+use spin::Mutex;
+
+use crate::{backtrace::return_addresses, serial_println};
+
+/// Live allocations that can be tracked at once. The table can't live on the heap it tracks,
+/// so it is a static that only takes up space when the `leak_tracker` feature is on.
+const CAPACITY: usize = if cfg!(feature = "leak_tracker") { 4096 } else { 1 };
+/// how many return addresses are recorded per allocation
+pub const TRACE_DEPTH: usize = 4;
+/// frames belonging to the allocator itself that are left out of traces
+const TRACE_SKIP: usize = 1;
+
+/// A live allocation recorded by the `LeakTracker`
+#[derive(Debug, Clone, Copy)]
+pub struct Leak {
+    pub ptr: usize,
+    pub size: usize,
+    /// return addresses of the allocating call chain, innermost first
+    pub trace: [usize; TRACE_DEPTH],
+    /// order of the allocation, used to compare against snapshots
+    seq: u64,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes at {:#x}, allocated at", self.size, self.ptr)?;
+        for addr in self.trace.iter().take_while(|&&addr| addr != 0) {
+            write!(f, " {addr:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// open addressing table of live allocations, keyed by pointer
+struct Records {
+    slots: [Option<Leak>; CAPACITY],
+    next_seq: u64,
+    /// allocations that didn't fit in the table
+    untracked: usize,
+}
+
+static RECORDS: Mutex<Records> = Mutex::new(Records {
+    slots: [None; CAPACITY],
+    next_seq: 0,
+    untracked: 0,
+});
+
+// CAPACITY is 1 without the feature
+#[allow(clippy::modulo_one)]
+impl Records {
+    fn home(ptr: usize) -> usize {
+        // fibonacci hashing, heap pointers are too regular to use directly
+        ((ptr as u64 >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % CAPACITY
+    }
+
+    fn insert(&mut self, ptr: usize, size: usize, trace: [usize; TRACE_DEPTH]) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut idx = Self::home(ptr);
+        for _ in 0..CAPACITY {
+            if self.slots[idx].is_none() {
+                self.slots[idx] = Some(Leak { ptr, size, trace, seq });
+                return;
+            }
+            idx = (idx + 1) % CAPACITY;
+        }
+        self.untracked += 1;
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        let mut idx = Self::home(ptr);
+        for _ in 0..CAPACITY {
+            match self.slots[idx] {
+                None => return, // never tracked
+                Some(leak) if leak.ptr == ptr => break,
+                Some(_) => idx = (idx + 1) % CAPACITY,
+            }
+        }
+        if self.slots[idx].is_none_or(|leak| leak.ptr != ptr) {
+            return;
+        }
+        self.slots[idx] = None;
+
+        // shift the rest of the probe run back so lookups never stop at the hole early
+        let mut hole = idx;
+        let mut next = (idx + 1) % CAPACITY;
+        while let Some(leak) = self.slots[next] {
+            let home = Self::home(leak.ptr);
+            // distance from home going forward, the entry can move to the hole if that is closer
+            let dist_next = (next + CAPACITY - home) % CAPACITY;
+            let dist_hole = (hole + CAPACITY - home) % CAPACITY;
+            if dist_hole < dist_next {
+                self.slots[hole] = self.slots[next].take();
+                hole = next;
+            }
+            next = (next + 1) % CAPACITY;
+        }
+    }
+}
+
+/// Records every live allocation of the wrapped allocator, enabled by the `leak_tracker`
+/// feature. Use `snapshot` to find allocations a piece of code left behind.
+pub struct LeakTracker<A> {
+    inner: A,
+}
+
+impl<A> LeakTracker<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for LeakTracker<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            let trace = return_addresses(TRACE_SKIP);
+            RECORDS.lock().insert(ptr as usize, layout.size(), trace);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        RECORDS.lock().remove(ptr as usize);
+        unsafe { self.inner.dealloc(ptr, layout) };
+    }
+}
+
+/// Marks a point in time, allocations made after it that are still alive count as leaks
+#[derive(Debug, Clone, Copy)]
+pub struct LeakSnapshot {
+    seq: u64,
+}
+
+/// Takes a snapshot of the allocations that are alive right now
+pub fn snapshot() -> LeakSnapshot {
+    LeakSnapshot { seq: RECORDS.lock().next_seq }
+}
+
+/// Number of allocations that were made while the table was full and are not tracked
+pub fn untracked() -> usize {
+    RECORDS.lock().untracked
+}
+
+impl LeakSnapshot {
+    /// Calls `f` for every allocation made since the snapshot that is still alive.
+    ///
+    /// The tracker is locked meanwhile, so `f` must not allocate.
+    pub fn for_each_leak(&self, f: impl FnMut(&Leak)) {
+        let records = RECORDS.lock();
+        records.slots
+            .iter()
+            .flatten()
+            .filter(|leak| leak.seq >= self.seq)
+            .for_each(f);
+    }
+
+    pub fn leak_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_leak(|_| count += 1);
+        count
+    }
+
+    pub fn leaked_bytes(&self) -> usize {
+        let mut bytes = 0;
+        self.for_each_leak(|leak| bytes += leak.size);
+        bytes
+    }
+
+    /// Prints every leak over serial and panics if there was any
+    pub fn assert_no_leaks(&self) {
+        let mut count = 0;
+        self.for_each_leak(|leak| {
+            serial_println!("leak: {}", leak);
+            count += 1;
+        });
+        assert_eq!(count, 0, "{} allocations leaked", count);
+    }
+}
+
+/// Runs `f` and panics if it leaked any allocation
+pub fn assert_no_leaks<R>(f: impl FnOnce() -> R) -> R {
+    let snapshot = snapshot();
+    let result = f();
+    snapshot.assert_no_leaks();
+    result
+}
@@ -0,0 +1,284 @@
+use core::{alloc::{GlobalAlloc, Layout}, iter, ptr::{NonNull, null_mut}};
+use linked_list_allocator::align_up;
+
+use crate::allocator::{GrowFn, HeapStats, KernelHeap, Locked, Usage, grow_size};
+
+/// a free region, written at the start of the region itself
+#[repr(C)]
+struct ListNode {
+    size: usize,
+    next: Option<*mut ListNode>,
+}
+
+/// A first-fit heap keeping its free regions in an address-sorted linked list.
+///
+/// Neighbouring free regions are merged on deallocation. Unlike `linked_list_allocator::Heap`
+/// it lets us look at the free regions, which is needed for fragmentation statistics.
+pub struct LinkedListAllocator {
+    head: Option<*mut ListNode>,
+    bottom: usize,
+    size: usize,
+    used: usize,
+    max_size: usize,
+    grow: Option<GrowFn>,
+    /// only updated when this is the global allocator itself
+    usage: Usage,
+}
+
+unsafe impl Send for LinkedListAllocator {}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            bottom: 0,
+            size: 0,
+            used: 0,
+            max_size: 0,
+            grow: None,
+            usage: Usage::new(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid and the memory is
+    /// unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.bottom = heap_start;
+        self.size = heap_size;
+        unsafe { self.add_free_region(heap_start, heap_size) };
+    }
+
+    /// Adds `by` bytes directly after the current heap end.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the memory behind `top()` is mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        let top = self.top();
+        self.size += by;
+        unsafe { self.add_free_region(top, by) };
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+
+    /// Iterates over the free regions as `(address, size)`, lowest address first
+    pub fn holes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        iter::successors(self.head, |&node| unsafe { (*node).next })
+            .map(|node| (node as usize, unsafe { (*node).size }))
+    }
+
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (size, align) = size_align(layout);
+
+        let mut prev: Option<*mut ListNode> = None;
+        let mut current = self.head;
+        while let Some(node) = current {
+            let region_start = node as usize;
+            let region_end = region_start + unsafe { (*node).size };
+            let next = unsafe { (*node).next };
+
+            if let Some(alloc_start) = alloc_from_region(region_start, region_end, size, align) {
+                match prev {
+                    Some(prev) => unsafe { (*prev).next = next },
+                    None => self.head = next,
+                }
+
+                // give back whatever the allocation does not cover
+                let alloc_end = alloc_start + size;
+                unsafe {
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if region_end > alloc_end {
+                        self.add_free_region(alloc_end, region_end - alloc_end);
+                    }
+                }
+
+                self.used += size;
+                return NonNull::new(alloc_start as *mut u8);
+            }
+
+            prev = current;
+            current = next;
+        }
+
+        None
+    }
+
+    /// Like `allocate_first_fit`, but grows the heap when no free region fits
+    pub fn allocate_or_grow(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        loop {
+            if let Some(ptr) = self.allocate_first_fit(layout) {
+                return Some(ptr);
+            }
+            if !self.grow_heap(&layout) {
+                return None;
+            }
+        }
+    }
+
+    /// maps enough new memory at the heap end to fit `layout`, returns false if nothing was mapped
+    fn grow_heap(&mut self, layout: &Layout) -> bool {
+        let Some(grow) = self.grow else {
+            return false;
+        };
+
+        // the free space at the old end might not be usable because of alignment, so ask for some slack
+        let size = grow_size(layout.size() + layout.align(), self.size, self.max_size);
+        if size == 0 {
+            return false;
+        }
+
+        let mapped = grow(self.top(), size);
+        if mapped == 0 {
+            return false;
+        }
+        unsafe { self.extend(mapped) };
+        true
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `allocate_first_fit` on this heap with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = size_align(layout);
+        self.used -= size;
+        unsafe { self.add_free_region(ptr.as_ptr() as usize, size) };
+    }
+
+    /// inserts the region into the sorted list and merges it with its direct neighbours
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, align_of::<ListNode>()), addr);
+        assert!(size >= size_of::<ListNode>());
+
+        let mut prev: Option<*mut ListNode> = None;
+        let mut next = self.head;
+        while let Some(node) = next {
+            if node as usize > addr {
+                break;
+            }
+            prev = next;
+            next = unsafe { (*node).next };
+        }
+
+        let new = addr as *mut ListNode;
+        unsafe {
+            new.write(ListNode { size, next });
+
+            if let Some(next) = next
+                && addr + size == next as usize {
+                    (*new).size += (*next).size;
+                    (*new).next = (*next).next;
+                }
+
+            match prev {
+                Some(prev) if prev as usize + (*prev).size == addr => {
+                    (*prev).size += (*new).size;
+                    (*prev).next = (*new).next;
+                }
+                Some(prev) => (*prev).next = Some(new),
+                None => self.head = Some(new),
+            }
+        }
+    }
+}
+
+impl KernelHeap for LinkedListAllocator {
+    unsafe fn init_heap(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.init(heap_start, heap_size) };
+    }
+
+    fn set_growth(&mut self, max_heap_size: usize, grow: GrowFn) {
+        self.max_size = max_heap_size;
+        self.grow = Some(grow);
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.usage.bytes_in_use,
+            high_water_mark: self.usage.high_water_mark,
+            allocations: self.usage.allocations,
+            free_blocks: Default::default(),
+            heap_size: self.size,
+            fallback_free: self.free(),
+            largest_free_hole: self.holes().map(|(_, size)| size).max().unwrap_or(0),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match allocator.allocate_or_grow(layout) {
+            Some(ptr) => {
+                allocator.usage.record_alloc(layout.size());
+                ptr.as_ptr()
+            }
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.usage.record_dealloc(layout.size());
+        let ptr = NonNull::new(ptr).expect("Null pointer");
+        unsafe { allocator.deallocate(ptr, layout) };
+    }
+}
+
+/// adjusts the layout so every allocated or freed region can hold a `ListNode`
+fn size_align(layout: Layout) -> (usize, usize) {
+    let layout = layout
+        .align_to(align_of::<ListNode>())
+        .expect("adjusting alignment failed")
+        .pad_to_align();
+    let size = align_up(layout.size().max(size_of::<ListNode>()), size_of::<ListNode>());
+    (size, layout.align())
+}
+
+/// returns where an allocation would start inside the region, if it fits
+fn alloc_from_region(start: usize, end: usize, size: usize, align: usize) -> Option<usize> {
+    let mut alloc_start = align_up(start, align);
+    // the space in front has to be big enough to stay a free region
+    if alloc_start != start && alloc_start - start < size_of::<ListNode>() {
+        alloc_start = align_up(start + size_of::<ListNode>(), align);
+    }
+
+    let alloc_end = alloc_start.checked_add(size)?;
+    if alloc_end > end {
+        return None;
+    }
+
+    let excess = end - alloc_end;
+    if excess > 0 && excess < size_of::<ListNode>() {
+        return None;
+    }
+
+    Some(alloc_start)
+}
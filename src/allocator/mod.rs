@@ -1,20 +1,144 @@
 pub mod bump;
+pub mod debug;
 pub mod fixed_size;
+pub mod kmem_cache;
+pub mod leak_tracker;
+pub mod linked_list;
 
-use core::{alloc::GlobalAlloc, ptr::null_mut};
-use x86_64::{VirtAddr, structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError}};
-use crate::allocator::fixed_size::FixedSizeBlockAllocator;
+use core::{alloc::GlobalAlloc, fmt, ptr::null_mut};
+use x86_64::{VirtAddr, structures::paging::{PageSize, PageTableFlags, Size2MiB}};
+use linked_list_allocator::align_up;
+use crate::{allocator::fixed_size::BLOCK_SIZES, memory::{self, huge, vmm::{self, VmmError}}};
+#[cfg(feature = "debug_heap")]
+use crate::allocator::debug::DebugAllocator;
+#[cfg(feature = "leak_tracker")]
+use crate::allocator::leak_tracker::LeakTracker;
+
+#[cfg(any(
+    all(feature = "alloc_bump", feature = "alloc_linked_list"),
+    all(feature = "alloc_bump", feature = "alloc_fixed_size"),
+    all(feature = "alloc_linked_list", feature = "alloc_fixed_size"),
+    not(any(feature = "alloc_bump", feature = "alloc_linked_list", feature = "alloc_fixed_size")),
+))]
+compile_error!("select exactly one of the `alloc_bump`, `alloc_linked_list` and `alloc_fixed_size` features");
+
+#[cfg(feature = "alloc_bump")]
+type HeapAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc_linked_list")]
+type HeapAllocator = linked_list::LinkedListAllocator;
+#[cfg(not(any(feature = "alloc_bump", feature = "alloc_linked_list")))]
+type HeapAllocator = fixed_size::FixedSizeBlockAllocator;
 
 #[alloc_error_handler]
 fn alloc_error(layout: core::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout);
 }
 
+#[cfg(feature = "debug_heap")]
+type Checked = DebugAllocator<Locked<HeapAllocator>>;
+#[cfg(not(feature = "debug_heap"))]
+type Checked = Locked<HeapAllocator>;
+
+#[cfg(feature = "leak_tracker")]
+type GlobalHeap = LeakTracker<Checked>;
+#[cfg(not(feature = "leak_tracker"))]
+type GlobalHeap = Checked;
+
+/// wraps the selected allocator in the layers enabled by cargo features
+const fn global_heap() -> GlobalHeap {
+    let heap = Locked::new(HeapAllocator::new());
+    #[cfg(feature = "debug_heap")]
+    let heap = DebugAllocator::new(heap);
+    #[cfg(feature = "leak_tracker")]
+    let heap = LeakTracker::new(heap);
+    heap
+}
+
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: GlobalHeap = global_heap();
+
+/// the allocator below the `leak_tracker` and `debug_heap` layers
+fn heap() -> &'static Locked<HeapAllocator> {
+    let heap = &ALLOCATOR;
+    #[cfg(feature = "leak_tracker")]
+    let heap = heap.inner();
+    #[cfg(feature = "debug_heap")]
+    let heap = heap.inner();
+    heap
+}
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; //100KiB
+/// the heap grows on demand up to this size, see `KernelHeap::set_growth`
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024; // 32MiB
+
+/// a growing heap asks for at least this much at a time
+const MIN_GROW_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// Maps `size` more bytes of heap starting at `heap_end`, returns how many bytes it mapped
+pub type GrowFn = fn(heap_end: usize, size: usize) -> usize;
+
+/// What `init_heap` and `stats` need from the allocator behind `ALLOCATOR`,
+/// which one that is gets picked by the `alloc_*` cargo features.
+pub trait KernelHeap {
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid and the memory is
+    /// unused. This method must be called only once.
+    unsafe fn init_heap(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Lets the heap grow past its end up to `max_heap_size` bytes in total, calling `grow`
+    /// to map the new memory whenever it runs out.
+    fn set_growth(&mut self, max_heap_size: usize, grow: GrowFn);
+
+    fn stats(&self) -> HeapStats;
+}
+
+/// Counts the live allocations of a global allocator
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub bytes_in_use: usize,
+    pub high_water_mark: usize,
+    pub allocations: usize,
+}
+
+impl Usage {
+    pub const fn new() -> Self {
+        Self {
+            bytes_in_use: 0,
+            high_water_mark: 0,
+            allocations: 0,
+        }
+    }
+
+    pub fn record_alloc(&mut self, size: usize) {
+        self.bytes_in_use += size;
+        self.high_water_mark = self.high_water_mark.max(self.bytes_in_use);
+        self.allocations += 1;
+    }
+
+    pub fn record_dealloc(&mut self, size: usize) {
+        self.bytes_in_use -= size;
+        self.allocations -= 1;
+    }
+
+    pub fn record_realloc(&mut self, old_size: usize, new_size: usize) {
+        self.bytes_in_use = self.bytes_in_use - old_size + new_size;
+        self.high_water_mark = self.high_water_mark.max(self.bytes_in_use);
+    }
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// how many bytes a heap of `heap_size` should grow by to fit `needed` more, 0 if it can't
+fn grow_size(needed: usize, heap_size: usize, max_heap_size: usize) -> usize {
+    let wanted = align_up(needed, PAGE_SIZE).max(MIN_GROW_SIZE);
+    wanted.min(max_heap_size.saturating_sub(heap_size))
+}
 pub struct DummyAllocator;
 
 unsafe impl GlobalAlloc for DummyAllocator {
@@ -27,37 +151,93 @@ unsafe impl GlobalAlloc for DummyAllocator {
     }
 }
 
-pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1;
-
-        let start_page: Page = Page::containing_address(heap_start);
-        let end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(start_page, end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            let res = mapper.map_to(page, frame, flags, frame_allocator);
-            if let Err(e) = res {
-                panic!("Mapping page {page:?} failed: {:?}", e);
-            }
-            res.unwrap().flush()
-        };
+/// A snapshot of what the global allocator is doing, see `stats`.
+///
+/// With `debug_heap` the byte counts include the headers and redzones of every allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// bytes requested by allocations that are still alive
+    pub bytes_in_use: usize,
+    /// the most `bytes_in_use` has ever been
+    pub high_water_mark: usize,
+    /// number of allocations that are still alive
+    pub allocations: usize,
+    /// cached free blocks for every `BLOCK_SIZES` class, all zero for other allocators
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+    /// mapped size of the heap
+    pub heap_size: usize,
+    /// free bytes in the (fallback) heap, not counting cached blocks
+    pub fallback_free: usize,
+    /// the largest allocation the (fallback) heap can serve without growing
+    pub largest_free_hole: usize,
+}
+
+impl HeapStats {
+    /// Percentage of free fallback memory that is not part of the largest hole.
+    /// 0 means all free memory is in one piece.
+    pub fn fragmentation(&self) -> usize {
+        match self.fallback_free {
+            0 => 0,
+            free => 100 - self.largest_free_hole * 100 / free,
+        }
     }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "heap: {} bytes in use by {} allocations, high-water mark {} bytes",
+            self.bytes_in_use, self.allocations, self.high_water_mark)?;
+        writeln!(f, "fallback: {} bytes mapped, {} free, largest hole {} ({}% fragmented)",
+            self.heap_size, self.fallback_free, self.largest_free_hole, self.fragmentation())?;
+        write!(f, "free blocks:")?;
+        for (size, count) in BLOCK_SIZES.iter().zip(self.free_blocks) {
+            write!(f, " {size}B={count}")?;
+        }
+        Ok(())
+    }
+}
 
+/// Returns a snapshot of the global allocator's statistics.
+///
+/// Takes the allocator lock, so it must not be called from inside the allocator.
+pub fn stats() -> HeapStats {
+    heap().lock().stats()
+}
+
+/// Reserves `HEAP_MAX_SIZE` of address space from the `vmm` and maps the first `HEAP_SIZE`
+/// of it, `memory::init_global` has to run first.
+pub fn init_heap() -> Result<(), VmmError> {
+    // aligned so growth can switch to huge pages
+    let heap_start = vmm::reserve_aligned("kernel heap", HEAP_MAX_SIZE as u64, huge::best_alignment(HEAP_MAX_SIZE as u64))?;
+    vmm::map(heap_start, HEAP_SIZE as u64, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?;
+
+    let mut allocator = heap().lock();
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        allocator.init_heap(heap_start.as_u64() as usize, HEAP_SIZE);
     }
+    allocator.set_growth(HEAP_MAX_SIZE, grow_heap);
 
     Ok(())
 }
 
+/// Maps more heap through the global mapper of `memory::init_global`.
+///
+/// Called with the allocator locked, so this must not allocate or lock the `vmm`. The heap's
+/// reservation already covers `HEAP_MAX_SIZE`, so the pages can be mapped directly. Once the
+/// heap end reaches a 2 MiB boundary it grows in whole huge pages.
+fn grow_heap(heap_end: usize, size: usize) -> usize {
+    let start = VirtAddr::new(heap_end as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let huge_size = if start.is_aligned(Size2MiB::SIZE) { size.next_multiple_of(Size2MiB::SIZE as usize) } else { size };
+
+    memory::try_with_memory(|mapper, frame_allocator| {
+        [huge_size, size]
+            .into_iter()
+            .find(|&size| huge::map_frames(mapper, frame_allocator, start, size as u64, flags).is_ok())
+            .unwrap_or(0)
+    }).unwrap_or(0)
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>
 }
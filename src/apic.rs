@@ -0,0 +1,349 @@
+use core::{arch::x86_64::__cpuid, sync::atomic::{AtomicBool, Ordering}};
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+
+use crate::{interrupts::PICS, memory::mmio::{self, CacheMode, MmioRegion}};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+
+/// Vector of the spurious interrupts the local APIC sends now and then, they need no EOI
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// I/O APIC registers, reached through a select and a data window
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// redirection entry bits
+const MASKED: u64 = 1 << 16;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ACTIVE_LOW: u64 = 1 << 13;
+
+/// number of ISA IRQs, the ones the MADT can override
+const ISA_IRQS: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Once<MmioRegion> = Once::new();
+static IO_APIC: Once<Mutex<IoApic>> = Once::new();
+static ISA_ROUTES: Once<[IsaRoute; ISA_IRQS]> = Once::new();
+
+/// Where an ISA IRQ ends up on the I/O APIC, after the MADT's source overrides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl IsaRoute {
+    /// ISA interrupts are edge triggered and active high unless overridden
+    const fn identity(irq: u8) -> Self {
+        Self { gsi: irq as u32, active_low: false, level_triggered: false }
+    }
+}
+
+struct IoApic {
+    region: MmioRegion,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.region.write(IOREGSEL, register);
+        self.region.read(IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.region.write(IOREGSEL, register);
+        self.region.write(IOWIN, value);
+    }
+
+    fn redirection(&self, gsi: u32) -> Option<u64> {
+        let index = self.index(gsi)?;
+        let low = self.read(IOREDTBL + index * 2);
+        let high = self.read(IOREDTBL + index * 2 + 1);
+        Some(u64::from(high) << 32 | u64::from(low))
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) -> bool {
+        let Some(index) = self.index(gsi) else {
+            return false;
+        };
+        // mask first, so the entry is never live half written
+        self.write(IOREDTBL + index * 2, MASKED as u32);
+        self.write(IOREDTBL + index * 2 + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + index * 2, entry as u32);
+        true
+    }
+
+    fn index(&self, gsi: u32) -> Option<u32> {
+        gsi.checked_sub(self.gsi_base).filter(|&index| index < self.entries)
+    }
+}
+
+/// Whether the CPU has a local APIC, CPUID.01h:EDX bit 9
+// `__cpuid` is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Whether interrupts go through the APICs, as opposed to the 8259 PICs
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Finds the local APIC and the I/O APIC through the ACPI MADT, remaps and masks the 8259 PICs,
+/// enables the local APIC and masks every I/O APIC entry until `irq` routes it.
+///
+/// Returns false, changing nothing, if there is no APIC or no MADT describing it. Needs
+/// `memory::init_global`.
+pub(crate) fn init() -> bool {
+    if !is_supported() {
+        return false;
+    }
+    let Some(madt) = acpi::find_madt() else {
+        return false;
+    };
+    let Some((io_apic_addr, gsi_base)) = madt.io_apic else {
+        return false;
+    };
+
+    let Ok(local_apic) = (unsafe { mmio::map_mmio(madt.local_apic, 0x400, CacheMode::Uncacheable) }) else {
+        return false;
+    };
+    let Ok(region) = (unsafe { mmio::map_mmio(io_apic_addr, 0x20, CacheMode::Uncacheable) }) else {
+        return false;
+    };
+    let mut io_apic = IoApic { region, gsi_base, entries: 0 };
+    io_apic.entries = (io_apic.read(IOAPICVER) >> 16 & 0xff) + 1;
+
+    // the PICs still raise spurious interrupts when masked, keep them away from the exceptions
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.disable();
+    }
+
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        let value = base.read();
+        base.write(value | APIC_BASE_ENABLE);
+    }
+    local_apic.write(LAPIC_TPR, 0u32);
+    local_apic.write(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+    // nothing is delivered until an entry is unmasked
+    for index in 0..io_apic.entries {
+        io_apic.set_redirection(gsi_base + index, MASKED);
+    }
+
+    ISA_ROUTES.call_once(|| madt.isa_routes);
+    LOCAL_APIC.call_once(|| local_apic);
+    IO_APIC.call_once(|| Mutex::new(io_apic));
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// ID of the local APIC of this CPU, `None` if the APICs are not enabled
+pub fn local_apic_id() -> Option<u8> {
+    LOCAL_APIC.get().map(|lapic| (lapic.read::<u32>(LAPIC_ID) >> 24) as u8)
+}
+
+/// Signals the end of the current interrupt to the local APIC
+pub fn end_of_interrupt() {
+    if let Some(lapic) = LOCAL_APIC.get() {
+        lapic.write(LAPIC_EOI, 0u32);
+    }
+}
+
+/// Starts the local APIC timer counting down from `initial_count` at the bus clock divided
+/// by 16. With a vector it interrupts periodically, without it counts down once silently, which
+/// is how it gets calibrated. Returns false if the APICs are not enabled.
+pub fn start_timer(initial_count: u32, vector: Option<u8>) -> bool {
+    let Some(lapic) = LOCAL_APIC.get() else {
+        return false;
+    };
+    let lvt = match vector {
+        Some(vector) => LVT_PERIODIC | u32::from(vector),
+        None => LVT_MASKED,
+    };
+    lapic.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic.write(LAPIC_LVT_TIMER, lvt);
+    // writing the initial count starts the timer
+    lapic.write(LAPIC_TIMER_INITIAL, initial_count);
+    true
+}
+
+/// What is left of the local APIC timer's count
+pub fn timer_count() -> Option<u32> {
+    LOCAL_APIC.get().map(|lapic| lapic.read::<u32>(LAPIC_TIMER_CURRENT))
+}
+
+pub fn stop_timer() {
+    if let Some(lapic) = LOCAL_APIC.get() {
+        lapic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic.write(LAPIC_TIMER_INITIAL, 0u32);
+    }
+}
+
+/// Where ISA IRQ `irq` arrives at the I/O APIC
+pub fn isa_route(irq: u8) -> IsaRoute {
+    ISA_ROUTES
+        .get()
+        .and_then(|routes| routes.get(usize::from(irq)).copied())
+        .unwrap_or(IsaRoute::identity(irq))
+}
+
+/// Delivers ISA IRQ `irq` as `vector` to this CPU. Returns false if the APICs are not enabled
+/// or the I/O APIC has no entry for the IRQ.
+pub fn route_isa_irq(irq: u8, vector: u8) -> bool {
+    let route = isa_route(irq);
+    let mut entry = u64::from(vector);
+    if route.active_low {
+        entry |= ACTIVE_LOW;
+    }
+    if route.level_triggered {
+        entry |= LEVEL_TRIGGERED;
+    }
+    let Some(destination) = local_apic_id() else {
+        return false;
+    };
+    entry |= u64::from(destination) << 56;
+    IO_APIC.get().is_some_and(|io_apic| io_apic.lock().set_redirection(route.gsi, entry))
+}
+
+/// Stops or resumes delivery of ISA IRQ `irq`. Returns false if it can't be reached.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> bool {
+    let gsi = isa_route(irq).gsi;
+    IO_APIC.get().is_some_and(|io_apic| {
+        let io_apic = io_apic.lock();
+        let Some(entry) = io_apic.redirection(gsi) else {
+            return false;
+        };
+        let entry = if masked { entry | MASKED } else { entry & !MASKED };
+        io_apic.set_redirection(gsi, entry)
+    })
+}
+
+/// The raw redirection entry of ISA IRQ `irq`, for diagnostics
+pub fn isa_redirection(irq: u8) -> Option<u64> {
+    IO_APIC.get()?.lock().redirection(isa_route(irq).gsi)
+}
+
+/// Just enough ACPI to read the MADT
+mod acpi {
+    use super::{ISA_IRQS, IsaRoute};
+    use crate::memory;
+    use x86_64::PhysAddr;
+
+    const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+    const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+    const HEADER_LEN: u64 = 36;
+
+    // MADT entry types
+    const IO_APIC: u8 = 1;
+    const SOURCE_OVERRIDE: u8 = 2;
+    const LOCAL_APIC_OVERRIDE: u8 = 5;
+
+    pub(super) struct Madt {
+        pub local_apic: PhysAddr,
+        /// address and first GSI of the I/O APIC handling GSI 0
+        pub io_apic: Option<(PhysAddr, u32)>,
+        pub isa_routes: [IsaRoute; ISA_IRQS],
+    }
+
+    fn read<T: Copy>(addr: u64) -> T {
+        unsafe { memory::phys_to_virt(PhysAddr::new(addr)).as_ptr::<T>().read_unaligned() }
+    }
+
+    fn checksum_ok(addr: u64, len: u64) -> bool {
+        (addr..addr + len).fold(0u8, |sum, a| sum.wrapping_add(read::<u8>(a))) == 0
+    }
+
+    /// the RSDP is in the first KiB of the EBDA or in the BIOS area below 1 MiB
+    fn find_rsdp() -> Option<u64> {
+        let ebda = u64::from(read::<u16>(0x40e)) << 4;
+        let ebda = (ebda != 0).then_some(ebda..ebda + 1024);
+        ebda.into_iter()
+            .chain(core::iter::once(0xe_0000..0x10_0000))
+            .flat_map(|range| range.step_by(16))
+            .find(|&addr| read::<[u8; 8]>(addr) == *RSDP_SIGNATURE && checksum_ok(addr, 20))
+    }
+
+    pub(super) fn find_madt() -> Option<Madt> {
+        let rsdp = find_rsdp()?;
+        let revision = read::<u8>(rsdp + 15);
+        // the XSDT has 64 bit entries, the RSDT 32 bit ones
+        let (sdt, entry_size) = if revision >= 2 && read::<u64>(rsdp + 24) != 0 {
+            (read::<u64>(rsdp + 24), 8)
+        } else {
+            (u64::from(read::<u32>(rsdp + 16)), 4)
+        };
+        let len = u64::from(read::<u32>(sdt + 4));
+        let madt = (sdt + HEADER_LEN..sdt + len).step_by(entry_size).map(|entry| match entry_size {
+            8 => read::<u64>(entry),
+            _ => u64::from(read::<u32>(entry)),
+        })
+        .find(|&table| read::<[u8; 4]>(table) == *MADT_SIGNATURE)?;
+        let len = u64::from(read::<u32>(madt + 4));
+        if !checksum_ok(madt, len) {
+            return None;
+        }
+
+        let mut result = Madt {
+            local_apic: PhysAddr::new(u64::from(read::<u32>(madt + HEADER_LEN))),
+            io_apic: None,
+            isa_routes: core::array::from_fn(|irq| IsaRoute::identity(irq as u8)),
+        };
+        let mut entry = madt + HEADER_LEN + 8;
+        while entry + 2 <= madt + len {
+            let (kind, entry_len) = (read::<u8>(entry), u64::from(read::<u8>(entry + 1)));
+            if entry_len < 2 {
+                break;
+            }
+            match kind {
+                IO_APIC => {
+                    let addr = PhysAddr::new(u64::from(read::<u32>(entry + 4)));
+                    let gsi_base = read::<u32>(entry + 8);
+                    if result.io_apic.is_none() || gsi_base == 0 {
+                        result.io_apic = Some((addr, gsi_base));
+                    }
+                }
+                SOURCE_OVERRIDE => {
+                    let source = usize::from(read::<u8>(entry + 3));
+                    let flags = read::<u16>(entry + 8);
+                    if let Some(route) = result.isa_routes.get_mut(source) {
+                        // 0b11 is active low / level triggered, anything else keeps the ISA default
+                        *route = IsaRoute {
+                            gsi: read::<u32>(entry + 4),
+                            active_low: flags & 0b11 == 0b11,
+                            level_triggered: flags >> 2 & 0b11 == 0b11,
+                        };
+                    }
+                }
+                LOCAL_APIC_OVERRIDE => result.local_apic = PhysAddr::new(read::<u64>(entry + 4)),
+                _ => {}
+            }
+            entry += entry_len;
+        }
+        Some(result)
+    }
+}
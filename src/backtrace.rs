@@ -0,0 +1,35 @@
+use core::arch::asm;
+
+/// Walks the frame pointer chain and returns up to `N` return addresses, innermost first.
+///
+/// The first `skip` frames above the caller are left out, unused slots are zero. Relies on
+/// the target spec keeping frame pointers in every function.
+#[inline(always)]
+pub fn return_addresses<const N: usize>(skip: usize) -> [usize; N] {
+    let mut addresses = [0; N];
+    let mut rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    let mut depth = 0;
+    while rbp != 0 && rbp.is_multiple_of(align_of::<usize>()) && depth < skip + N {
+        // [rbp] is the caller's rbp, [rbp + 8] the return address into the caller
+        let (next, ret) = unsafe {
+            let frame = rbp as *const usize;
+            (frame.read(), frame.add(1).read())
+        };
+        if ret == 0 {
+            break;
+        }
+        if depth >= skip {
+            addresses[depth - skip] = ret;
+        }
+        // stacks grow down, so a sane chain only ever moves up
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+        depth += 1;
+    }
+
+    addresses
+}
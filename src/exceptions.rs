@@ -0,0 +1,391 @@
+use core::{fmt, sync::atomic::{AtomicU8, Ordering}};
+use x86_64::{VirtAddr, registers::{control::{Cr0, Cr2, Cr3, Cr4}, model_specific::Efer, segmentation::{CS, DS, ES, FS, GS, SS, Segment}}, structures::idt::{ExceptionVector, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode}};
+
+use crate::{hlt_loop, interrupt_stats, println};
+
+/// What an exception handler does once the exception is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    /// panic with the name of the exception, the default for everything that can't be resumed
+    Panic,
+    /// stop the CPU for good
+    Halt,
+    /// return to the interrupted code, only possible for traps and NMIs
+    Resume,
+}
+
+static POLICIES: [AtomicU8; 32] = [const { AtomicU8::new(u8::MAX) }; 32];
+
+/// Sets the policy for `vector`. Returns false, leaving the policy alone, if `Resume` is asked
+/// for an exception that would just happen again.
+pub fn set_policy(vector: ExceptionVector, policy: Policy) -> bool {
+    if policy == Policy::Resume && !resumable(vector) {
+        return false;
+    }
+    POLICIES[vector as usize].store(policy as u8, Ordering::Relaxed);
+    true
+}
+
+pub fn policy(vector: ExceptionVector) -> Policy {
+    match POLICIES[vector as usize].load(Ordering::Relaxed) {
+        p if p == Policy::Panic as u8 => Policy::Panic,
+        p if p == Policy::Halt as u8 => Policy::Halt,
+        p if p == Policy::Resume as u8 => Policy::Resume,
+        _ if resumable(vector) => Policy::Resume,
+        _ => Policy::Panic,
+    }
+}
+
+/// traps and NMIs return past the instruction, faults would run into it again
+fn resumable(vector: ExceptionVector) -> bool {
+    matches!(
+        vector,
+        ExceptionVector::Debug | ExceptionVector::NonMaskableInterrupt | ExceptionVector::Breakpoint | ExceptionVector::Overflow
+    )
+}
+
+pub fn name(vector: ExceptionVector) -> &'static str {
+    match vector {
+        ExceptionVector::Division => "DIVIDE ERROR",
+        ExceptionVector::Debug => "DEBUG",
+        ExceptionVector::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+        ExceptionVector::Breakpoint => "BREAKPOINT",
+        ExceptionVector::Overflow => "OVERFLOW",
+        ExceptionVector::BoundRange => "BOUND RANGE EXCEEDED",
+        ExceptionVector::InvalidOpcode => "INVALID OPCODE",
+        ExceptionVector::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+        ExceptionVector::Double => "DOUBLE FAULT",
+        ExceptionVector::InvalidTss => "INVALID TSS",
+        ExceptionVector::SegmentNotPresent => "SEGMENT NOT PRESENT",
+        ExceptionVector::Stack => "STACK-SEGMENT FAULT",
+        ExceptionVector::GeneralProtection => "GENERAL PROTECTION FAULT",
+        ExceptionVector::Page => "PAGE FAULT",
+        ExceptionVector::X87FloatingPoint => "X87 FLOATING POINT",
+        ExceptionVector::AlignmentCheck => "ALIGNMENT CHECK",
+        ExceptionVector::MachineCheck => "MACHINE CHECK",
+        ExceptionVector::SimdFloatingPoint => "SIMD FLOATING POINT",
+        ExceptionVector::Virtualization => "VIRTUALIZATION",
+        ExceptionVector::ControlProtection => "CONTROL PROTECTION",
+        ExceptionVector::HypervisorInjection => "HYPERVISOR INJECTION",
+        ExceptionVector::VmmCommunication => "VMM COMMUNICATION",
+        ExceptionVector::Security => "SECURITY",
+        _ => "UNKNOWN EXCEPTION",
+    }
+}
+
+/// The error code an exception pushed, decoded as far as the vector tells how
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// #TS, #NP, #SS and #GP point at the segment selector or IDT entry that caused them
+    Selector { raw: u64, selector: SelectorErrorCode },
+    /// #CP says which kind of control transfer went wrong
+    ControlProtection(u64),
+    Page(PageFaultErrorCode),
+    Raw(u64),
+}
+
+impl ErrorCode {
+    pub fn decode(vector: ExceptionVector, code: u64) -> Self {
+        match vector {
+            ExceptionVector::InvalidTss
+            | ExceptionVector::SegmentNotPresent
+            | ExceptionVector::Stack
+            | ExceptionVector::GeneralProtection => {
+                ErrorCode::Selector { raw: code, selector: SelectorErrorCode::new_truncate(code) }
+            }
+            ExceptionVector::ControlProtection => ErrorCode::ControlProtection(code),
+            ExceptionVector::Page => ErrorCode::Page(PageFaultErrorCode::from_bits_truncate(code)),
+            _ => ErrorCode::Raw(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            // a zero selector error code means no selector was involved, e.g. a #GP from a
+            // non-canonical address or a privileged instruction
+            ErrorCode::Selector { raw: 0, .. } => write!(f, "0 (not caused by a selector)"),
+            ErrorCode::Selector { raw, selector } => {
+                write!(f, "{:#x}: index {} in the {:?}", raw, selector.index(), selector.descriptor_table())?;
+                if selector.external() {
+                    write!(f, ", during delivery of an external event")?;
+                }
+                Ok(())
+            }
+            ErrorCode::ControlProtection(code) => {
+                let cause = match code & 0x7fff {
+                    1 => "near ret",
+                    2 => "far ret or iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x}: {}", code, cause)?;
+                if code & (1 << 15) != 0 {
+                    write!(f, " in an enclave")?;
+                }
+                Ok(())
+            }
+            ErrorCode::Page(code) => write!(f, "{:#x}: {:?}", code.bits(), code),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// The general purpose registers when the exception happened, saved by the entry stub in the
+/// order it pushes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX: {:#018x}  RBX: {:#018x}  RCX: {:#018x}  RDX: {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI: {:#018x}  RDI: {:#018x}  RBP: {:#018x}  R8:  {:#018x}", self.rsi, self.rdi, self.rbp, self.r8)?;
+        writeln!(f, "R9:  {:#018x}  R10: {:#018x}  R11: {:#018x}  R12: {:#018x}", self.r9, self.r10, self.r11, self.r12)?;
+        write!(f, "R13: {:#018x}  R14: {:#018x}  R15: {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Everything an entry stub leaves on the stack, lowest address first
+#[repr(C)]
+pub struct ExceptionContext {
+    pub registers: Registers,
+    /// 0 for exceptions that push none
+    error_code: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+impl ExceptionContext {
+    /// The error code, if `vector` pushes one
+    pub fn error_code(&self, vector: ExceptionVector) -> Option<u64> {
+        has_error_code(vector).then_some(self.error_code)
+    }
+}
+
+fn has_error_code(vector: ExceptionVector) -> bool {
+    matches!(
+        vector,
+        ExceptionVector::Double
+            | ExceptionVector::InvalidTss
+            | ExceptionVector::SegmentNotPresent
+            | ExceptionVector::Stack
+            | ExceptionVector::GeneralProtection
+            | ExceptionVector::Page
+            | ExceptionVector::AlignmentCheck
+            | ExceptionVector::ControlProtection
+            | ExceptionVector::VmmCommunication
+            | ExceptionVector::Security
+    )
+}
+
+/// Defines an IDT entry point that saves the general purpose registers and calls `$handler`
+/// with the `ExceptionContext`, restoring them and returning from the interrupt if it returns.
+/// Exceptions without an error code get a 0 pushed in its place, so the layout is the same.
+macro_rules! entry_stub {
+    ($name:ident => $handler:path) => {
+        $crate::exceptions::entry_stub!(@stub $name, $handler, "push 0");
+    };
+    ($name:ident => $handler:path, error_code) => {
+        $crate::exceptions::entry_stub!(@stub $name, $handler, "");
+    };
+    (@stub $name:ident, $handler:path, $push_error_code:literal) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            core::arch::naked_asm!(
+                $push_error_code,
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                // the CPU aligned the stack before pushing its 5 words, the error code and the
+                // 15 registers leave it 8 bytes off for the call
+                "sub rsp, 8",
+                "cld",
+                "call {handler}",
+                "add rsp, 8",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "add rsp, 8",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+pub(crate) use entry_stub;
+
+/// The address of an `entry_stub`, for `Entry::set_handler_addr`
+pub(crate) fn entry_addr(stub: extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Prints the control and segment registers, the stack frame and `Registers` have the rest
+pub fn print_registers() {
+    println!("CR0: {:#x}  CR2: {:#x}  CR3: {:#x}  CR4: {:#x}  EFER: {:#x}",
+        Cr0::read_raw(), Cr2::read_raw(), Cr3::read_raw().0.start_address(), Cr4::read_raw(), Efer::read_raw());
+    println!("CS: {:#x}  SS: {:#x}  DS: {:#x}  ES: {:#x}  FS: {:#x}  GS: {:#x}",
+        CS::get_reg().0, SS::get_reg().0, DS::get_reg().0, ES::get_reg().0, FS::get_reg().0, GS::get_reg().0);
+}
+
+/// Prints what is known about the exception and applies its policy, what every handler does
+pub fn handle(vector: ExceptionVector, context: &ExceptionContext) {
+    report(vector, context);
+    apply_policy(vector, &context.stack_frame, context.error_code(vector));
+}
+
+/// Prints the name of the exception, the decoded error code, the stack frame and registers
+pub fn report(vector: ExceptionVector, context: &ExceptionContext) {
+    println!("EXCEPTION: {} (vector {})", name(vector), vector as u8);
+    if let Some(code) = context.error_code(vector) {
+        println!("Error code: {}", ErrorCode::decode(vector, code));
+    }
+    println!("{:#?}", context.stack_frame);
+    println!("{}", context.registers);
+    print_registers();
+}
+
+/// Does what the policy of `vector` says, returning only for `Policy::Resume`
+pub fn apply_policy(vector: ExceptionVector, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    match policy(vector) {
+        Policy::Resume => {}
+        Policy::Halt => hlt_loop(),
+        Policy::Panic => match error_code {
+            Some(code) => panic!("EXCEPTION: {} at {:?}, error code {}",
+                name(vector), stack_frame.instruction_pointer, ErrorCode::decode(vector, code)),
+            None => panic!("EXCEPTION: {} at {:?}", name(vector), stack_frame.instruction_pointer),
+        },
+    }
+}
+
+/// Like `apply_policy`, for exceptions that can't be resumed
+pub fn fail(vector: ExceptionVector, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    apply_policy(vector, stack_frame, error_code);
+    // only reachable if the policy says to resume, which can't be set for these
+    hlt_loop()
+}
+
+extern "C" fn exception_handler<const VECTOR: u8>(context: &ExceptionContext) {
+    let _measure = interrupt_stats::measure(VECTOR);
+    // only instantiated for the vectors below
+    let vector = ExceptionVector::try_from(VECTOR).expect("not an exception vector");
+    handle(vector, context);
+}
+
+macro_rules! handlers {
+    ($($name:ident => $vector:ident),* $(,)?) => {$(
+        entry_stub!($name => exception_handler::<{ ExceptionVector::$vector as u8 }>);
+    )*};
+}
+
+macro_rules! handlers_with_error_code {
+    ($($name:ident => $vector:ident),* $(,)?) => {$(
+        entry_stub!($name => exception_handler::<{ ExceptionVector::$vector as u8 }>, error_code);
+    )*};
+}
+
+handlers! {
+    divide_error_entry => Division,
+    debug_entry => Debug,
+    nmi_entry => NonMaskableInterrupt,
+    breakpoint_entry => Breakpoint,
+    overflow_entry => Overflow,
+    bound_range_entry => BoundRange,
+    invalid_opcode_entry => InvalidOpcode,
+    device_not_available_entry => DeviceNotAvailable,
+    x87_floating_point_entry => X87FloatingPoint,
+    simd_floating_point_entry => SimdFloatingPoint,
+    virtualization_entry => Virtualization,
+    hv_injection_entry => HypervisorInjection,
+}
+
+handlers_with_error_code! {
+    invalid_tss_entry => InvalidTss,
+    segment_not_present_entry => SegmentNotPresent,
+    stack_segment_entry => Stack,
+    general_protection_entry => GeneralProtection,
+    alignment_check_entry => AlignmentCheck,
+    cp_protection_entry => ControlProtection,
+    vmm_communication_entry => VmmCommunication,
+    security_entry => Security,
+}
+
+entry_stub!(machine_check_entry => machine_check_handler);
+
+extern "C" fn machine_check_handler(context: &ExceptionContext) -> ! {
+    interrupt_stats::count(ExceptionVector::MachineCheck as u8);
+    report(ExceptionVector::MachineCheck, context);
+    fail(ExceptionVector::MachineCheck, &context.stack_frame, None)
+}
+
+/// Installs a handler for every exception but the double and page faults, which `interrupts`
+/// handles itself
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(entry_addr(divide_error_entry));
+        idt.debug.set_handler_addr(entry_addr(debug_entry));
+        idt.non_maskable_interrupt.set_handler_addr(entry_addr(nmi_entry));
+        idt.breakpoint.set_handler_addr(entry_addr(breakpoint_entry));
+        idt.overflow.set_handler_addr(entry_addr(overflow_entry));
+        idt.bound_range_exceeded.set_handler_addr(entry_addr(bound_range_entry));
+        idt.invalid_opcode.set_handler_addr(entry_addr(invalid_opcode_entry));
+        idt.device_not_available.set_handler_addr(entry_addr(device_not_available_entry));
+        idt.invalid_tss.set_handler_addr(entry_addr(invalid_tss_entry));
+        idt.segment_not_present.set_handler_addr(entry_addr(segment_not_present_entry));
+        idt.stack_segment_fault.set_handler_addr(entry_addr(stack_segment_entry));
+        idt.general_protection_fault.set_handler_addr(entry_addr(general_protection_entry));
+        idt.x87_floating_point.set_handler_addr(entry_addr(x87_floating_point_entry));
+        idt.alignment_check.set_handler_addr(entry_addr(alignment_check_entry));
+        idt.machine_check.set_handler_addr(entry_addr(machine_check_entry));
+        idt.simd_floating_point.set_handler_addr(entry_addr(simd_floating_point_entry));
+        idt.virtualization.set_handler_addr(entry_addr(virtualization_entry));
+        idt.cp_protection_exception.set_handler_addr(entry_addr(cp_protection_entry));
+        idt.hv_injection_exception.set_handler_addr(entry_addr(hv_injection_entry));
+        idt.vmm_communication_exception.set_handler_addr(entry_addr(vmm_communication_entry));
+        idt.security_exception.set_handler_addr(entry_addr(security_entry));
+    }
+}
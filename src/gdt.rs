@@ -1,19 +1,19 @@
 use lazy_static::lazy_static;
-use x86_64::{VirtAddr, instructions::tables::load_tss, registers::segmentation::{CS, Segment}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}};
+use x86_64::{instructions::tables::load_tss, registers::segmentation::{CS, Segment}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}};
+use crate::memory::stack::KernelStack;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: u64 = 4096 * 5; // 20 kb
 
 lazy_static! {
+    // the stacks come from the vmm, so memory::init_global has to run before this
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 4 kb
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; // no proper memory management yet, just use an array
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            #[allow(clippy::let_and_return)]
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
+            // installed for good, so it is never freed
+            let stack = KernelStack::new("double fault stack", DOUBLE_FAULT_STACK_SIZE)
+                .expect("allocating the double fault stack failed");
+            stack.top()
         };
         tss
     };
@@ -0,0 +1,135 @@
+use core::{arch::x86_64::_rdtsc, fmt, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+
+const VECTORS: usize = 256;
+
+static COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static CYCLES: [Cycles; VECTORS] = [const { Cycles::new() }; VECTORS];
+/// rdtsc costs a few dozen cycles per interrupt, so latencies are only measured when asked for
+static TIMING: AtomicBool = AtomicBool::new(false);
+
+struct Cycles {
+    /// handler runs that were timed
+    timed: AtomicU64,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Cycles {
+    const fn new() -> Self {
+        Self { timed: AtomicU64::new(0), total: AtomicU64::new(0), min: AtomicU64::new(u64::MAX), max: AtomicU64::new(0) }
+    }
+
+    fn reset(&self) {
+        self.timed.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Turns measuring the cycles spent in handlers on or off, counting always happens
+pub fn set_timing(enabled: bool) {
+    TIMING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn timing_enabled() -> bool {
+    TIMING.load(Ordering::Relaxed)
+}
+
+/// Counts a hit on `vector` and, with timing on, measures until the returned guard is dropped.
+/// Every handler that returns creates one first thing.
+#[must_use]
+pub fn measure(vector: u8) -> Measurement {
+    count(vector);
+    let start = timing_enabled().then(rdtsc);
+    Measurement { vector, start }
+}
+
+/// Only counts a hit on `vector`, for handlers that never return
+pub fn count(vector: u8) {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returned by `measure`, records the cycles when dropped
+pub struct Measurement {
+    vector: u8,
+    start: Option<u64>,
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let cycles = rdtsc().wrapping_sub(start);
+        let stats = &CYCLES[usize::from(self.vector)];
+        stats.timed.fetch_add(1, Ordering::Relaxed);
+        stats.total.fetch_add(cycles, Ordering::Relaxed);
+        stats.min.fetch_min(cycles, Ordering::Relaxed);
+        stats.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Cycles spent in the handler of one vector, over the runs that were timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleStats {
+    pub timed: u64,
+    pub min: u64,
+    pub max: u64,
+    pub avg: u64,
+}
+
+/// A snapshot of one vector, see `snapshot`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub count: u64,
+    /// `None` until a run was timed
+    pub cycles: Option<CycleStats>,
+}
+
+impl fmt::Display for VectorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vector {:>3}: {:>10} hits", self.vector, self.count)?;
+        if let Some(cycles) = self.cycles {
+            write!(f, ", cycles min {} avg {} max {} over {} runs", cycles.min, cycles.avg, cycles.max, cycles.timed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The numbers of one vector right now. They are read one by one, so a snapshot taken while
+/// the vector fires may be off by a hit.
+pub fn snapshot(vector: u8) -> VectorStats {
+    let index = usize::from(vector);
+    let cycles = &CYCLES[index];
+    let timed = cycles.timed.load(Ordering::Relaxed);
+    VectorStats {
+        vector,
+        count: COUNTS[index].load(Ordering::Relaxed),
+        cycles: (timed > 0).then(|| CycleStats {
+            timed,
+            min: cycles.min.load(Ordering::Relaxed),
+            max: cycles.max.load(Ordering::Relaxed),
+            avg: cycles.total.load(Ordering::Relaxed) / timed,
+        }),
+    }
+}
+
+/// Snapshots of every vector that was hit at least once
+pub fn snapshot_all() -> impl Iterator<Item = VectorStats> {
+    (0..=u8::MAX).map(snapshot).filter(|stats| stats.count > 0)
+}
+
+/// Starts counting from zero again
+pub fn reset() {
+    for (count, cycles) in COUNTS.iter().zip(&CYCLES) {
+        count.store(0, Ordering::Relaxed);
+        cycles.reset();
+    }
+}
@@ -3,7 +3,7 @@ use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::{VirtAddr, instructions::port::Port, structures::idt::{InterruptDescriptorTable, InterruptStackFrame}};
 use lazy_static::lazy_static;
-use crate::{gdt::DOUBLE_FAULT_IST_INDEX, print, println};
+use crate::{apic, gdt::DOUBLE_FAULT_IST_INDEX, interrupt_stats, irq, println, time};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -25,12 +25,12 @@ impl InterruptIndex {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe { idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(DOUBLE_FAULT_IST_INDEX); }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+        exceptions::install(&mut idt);
+        unsafe { idt.double_fault.set_handler_addr(exceptions::entry_addr(double_fault_entry))
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault.set_handler_addr(exceptions::entry_addr(page_fault_entry)); }
+        irq::install(&mut idt);
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -39,35 +39,87 @@ pub fn init() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Sets up the APICs, or the 8259 PICs if there are none, and registers the timer and
+/// keyboard handlers. Interrupts stay disabled.
+pub fn init_controllers() {
+    if !apic::init() {
+        unsafe { PICS.lock().initialize(); }
+    }
+    irq::mask_all();
+    irq::register_irq(TIMER_IRQ, timer_interrupt).expect("timer IRQ can't be registered");
+    irq::register_irq(KEYBOARD_IRQ, keyboard_interrupt).expect("keyboard IRQ can't be registered");
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _err_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Tells whichever interrupt controller is in use that the interrupt at `vector` is handled
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector); }
+    }
 }
 
-use x86_64::structures::idt::PageFaultErrorCode;
-use crate::hlt_loop;
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, err_code: PageFaultErrorCode) {
+exceptions::entry_stub!(double_fault_entry => double_fault_handler, error_code);
+
+extern "C" fn double_fault_handler(context: &ExceptionContext) -> ! {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Error code: {:?}", err_code);
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+
+    interrupt_stats::count(ExceptionVector::Double as u8);
+    exceptions::report(ExceptionVector::Double, context);
+    // a fault on a guard page can't push its frame onto the overflowed stack, so it ends up here
+    if let Ok(addr) = Cr2::read()
+        && let Some(name) = stack::overflowed_stack(addr) {
+            panic!("EXCEPTION: DOUBLE FAULT\nstack overflow in `{}` at {:?}", name, addr);
+        }
+    exceptions::fail(ExceptionVector::Double, &context.stack_frame, context.error_code(ExceptionVector::Double))
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+use x86_64::structures::idt::{ExceptionVector, PageFaultErrorCode};
+use crate::{exceptions::{self, ExceptionContext}, memory::{address_space, demand, stack}};
+exceptions::entry_stub!(page_fault_entry => page_fault_handler, error_code);
 
-    let interrupt_idx = InterruptIndex::Timer.as_u8(); // timer idx
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(interrupt_idx);
+extern "C" fn page_fault_handler(context: &ExceptionContext) {
+    use x86_64::registers::control::Cr2;
+
+    let error_code = context.error_code(ExceptionVector::Page);
+    let err_code = PageFaultErrorCode::from_bits_truncate(error_code.unwrap_or(0));
+
+    let _measure = interrupt_stats::measure(ExceptionVector::Page as u8);
+    let addr = Cr2::read();
+    // a write to a page shared with a cloned address space, give it its own copy
+    if let Ok(&addr) = addr.as_ref()
+        && address_space::handle_cow_fault(addr, err_code) {
+            return;
+        }
+    // first touch of a demand paged region, map it and retry the access
+    let reason = match addr {
+        Ok(addr) => match demand::handle_page_fault(addr, err_code) {
+            Ok(()) => return,
+            Err(reason) => reason,
+        },
+        Err(_) => demand::DemandFault::NotRegistered,
+    };
+
+    exceptions::report(ExceptionVector::Page, context);
+    if let Ok(&addr) = addr.as_ref()
+        && let Some(name) = stack::overflowed_stack(addr) {
+            println!("Stack overflow in `{}`", name);
+        }
+    if err_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH) {
+        println!("Instruction fetch from a non-executable page");
+    } else if err_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+        println!("Write to read-only memory");
     }
+    println!("Accessed Address: {:?}", addr);
+    println!("Not handled: {}", reason);
+    exceptions::fail(ExceptionVector::Page, &context.stack_frame, error_code);
+}
+
+fn timer_interrupt(_irq: u8) {
+    time::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt(_irq: u8) {
     use pc_keyboard::{Keyboard, ScancodeSet1, layouts};
 
     lazy_static! {
@@ -90,12 +142,16 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     //         }
     //     }
     // }
+}
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+// the local APIC doesn't expect an EOI for these
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _measure = interrupt_stats::measure(apic::SPURIOUS_VECTOR);
 }
 
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+
 // PIC offsets range from 32..47, typically
 pub const PIC1_OFFSET: u8 = 32; // 32 + 8
 pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8; // 32 + 8 + 8
@@ -0,0 +1,161 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::idt::{InterruptDescriptorTable, InterruptStackFrame}};
+
+use crate::{apic, interrupt_stats, interrupts::{self, PIC1_OFFSET, PICS}};
+
+/// The ISA IRQ lines, delivered as vectors `PIC1_OFFSET..PIC1_OFFSET + IRQ_LINES`
+pub const IRQ_LINES: u8 = 16;
+/// How many handlers can share one line
+pub const MAX_SHARED: usize = 4;
+
+/// the master PIC's input the slave is wired to, it has to stay unmasked for IRQs 8 to 15
+const CASCADE_IRQ: u8 = 2;
+
+/// Runs in interrupt context with the IRQ number, the EOI is sent after all handlers ran
+pub type IrqHandler = fn(irq: u8);
+
+#[derive(Clone, Copy)]
+struct Slot {
+    handler: Option<IrqHandler>,
+    /// bumped on every register, so stale handles don't match once the slot is reused
+    generation: u32,
+}
+
+static HANDLERS: Mutex<[[Slot; MAX_SHARED]; IRQ_LINES as usize]> =
+    Mutex::new([[Slot { handler: None, generation: 0 }; MAX_SHARED]; IRQ_LINES as usize]);
+
+/// Identifies a registered handler, for `unregister_irq`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    irq: u8,
+    slot: usize,
+    generation: u32,
+}
+
+impl IrqHandle {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// not one of the `IRQ_LINES`
+    NoSuchLine(u8),
+    /// `MAX_SHARED` handlers are on the line already
+    LineFull(u8),
+    /// the interrupt controller has no way to deliver the line
+    Unroutable(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::NoSuchLine(irq) => write!(f, "there is no IRQ {}", irq),
+            IrqError::LineFull(irq) => write!(f, "IRQ {} has no room for another handler", irq),
+            IrqError::Unroutable(irq) => write!(f, "IRQ {} can't be routed", irq),
+        }
+    }
+}
+
+/// The vector IRQ `irq` arrives at
+pub const fn vector(irq: u8) -> u8 {
+    PIC1_OFFSET + irq
+}
+
+/// Adds `handler` to the line `irq`, unmasking the line if it is the first one.
+///
+/// Several handlers may share a line, each of them runs on every interrupt and has to check
+/// whether its device raised it.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    if irq >= IRQ_LINES {
+        return Err(IrqError::NoSuchLine(irq));
+    }
+    // dispatch takes the lock too
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[usize::from(irq)];
+        let first = line.iter().all(|slot| slot.handler.is_none());
+        let slot = line.iter().position(|slot| slot.handler.is_none()).ok_or(IrqError::LineFull(irq))?;
+        if first && !set_line_enabled(irq, true) {
+            return Err(IrqError::Unroutable(irq));
+        }
+        let generation = line[slot].generation.wrapping_add(1);
+        line[slot] = Slot { handler: Some(handler), generation };
+        Ok(IrqHandle { irq, slot, generation })
+    })
+}
+
+/// Removes a handler again, masking the line once nobody is left on it. Returns false if the
+/// handler was removed already.
+pub fn unregister_irq(handle: IrqHandle) -> bool {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[usize::from(handle.irq)];
+        let slot = &mut line[handle.slot];
+        if slot.generation != handle.generation || slot.handler.take().is_none() {
+            return false;
+        }
+        if line.iter().all(|slot| slot.handler.is_none()) {
+            set_line_enabled(handle.irq, false);
+        }
+        true
+    })
+}
+
+/// Number of handlers on `irq`
+pub fn handler_count(irq: u8) -> usize {
+    without_interrupts(|| HANDLERS.lock().get(usize::from(irq)).map_or(0, |line| line.iter().filter(|slot| slot.handler.is_some()).count()))
+}
+
+/// Points every IRQ vector at the dispatcher
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    macro_rules! stubs {
+        ($($irq:literal)*) => {$(
+            idt[vector($irq)].set_handler_fn(irq_handler::<$irq>);
+        )*};
+    }
+    stubs!(0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+}
+
+/// Masks every line, so only registered ones are delivered. Called once the controller is set up.
+pub(crate) fn mask_all() {
+    if apic::is_enabled() {
+        for irq in 0..IRQ_LINES {
+            apic::set_isa_irq_masked(irq, true);
+        }
+    } else {
+        unsafe { PICS.lock().write_masks(!(1 << CASCADE_IRQ), 0xff) };
+    }
+}
+
+fn set_line_enabled(irq: u8, enabled: bool) -> bool {
+    if apic::is_enabled() {
+        return if enabled { apic::route_isa_irq(irq, vector(irq)) } else { apic::set_isa_irq_masked(irq, true) };
+    }
+    let mut pics = PICS.lock();
+    let [mut master, mut slave] = unsafe { pics.read_masks() };
+    let (mask, bit) = if irq < 8 { (&mut master, irq) } else { (&mut slave, irq - 8) };
+    if enabled {
+        *mask &= !(1 << bit);
+    } else {
+        *mask |= 1 << bit;
+    }
+    unsafe { pics.write_masks(master, slave) };
+    true
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    let _measure = interrupt_stats::measure(vector(IRQ));
+    dispatch(IRQ);
+}
+
+fn dispatch(irq: u8) {
+    // copied out, so handlers may register and unregister
+    let line = HANDLERS.lock()[usize::from(irq)];
+    for handler in line.into_iter().filter_map(|slot| slot.handler) {
+        handler(irq);
+    }
+    interrupts::end_of_interrupt(vector(irq));
+}
@@ -11,18 +11,21 @@ extern crate alloc;
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
+pub mod apic;
+pub mod irq;
+pub mod exceptions;
+pub mod interrupt_stats;
+pub mod time;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod backtrace;
 
 use core::panic::PanicInfo;
-#[cfg(test)]
 use bootloader::BootInfo;
 use bootloader::entry_point;
 
-use crate::interrupts::PICS;
-
 /// combines both println! and serial_println!
 macro_rules! log {
     ($($arg:tt)*) => {{
@@ -87,18 +90,24 @@ pub fn hlt_loop() -> ! {
     }
 }
 
-pub fn init() {
+/// Sets up memory, the GDT with its interrupt stacks, the IDT, the interrupt controllers and
+/// the timer.
+///
+/// Must be called only once, with the boot info passed by the bootloader.
+pub fn init(boot_info: &'static BootInfo) {
+    unsafe { memory::init_global(boot_info) };
     interrupts::init();
     gdt::init();
-    unsafe { PICS.lock().initialize(); }
+    interrupts::init_controllers();
+    time::set_frequency(time::DEFAULT_FREQUENCY);
     x86_64::instructions::interrupts::enable();
 }
 
 #[cfg(test)]
 entry_point!(test_kernel_entry);
 #[cfg(test)]
-fn test_kernel_entry(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_entry(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop();
 }
@@ -12,13 +12,12 @@ use alloc::rc::Rc;
 use alloc::vec::Vec;
 use alloc::vec;
 use bootloader::{BootInfo, entry_point};
-use floof::memory::{BootInfoFrameAllocator, EmptyFrameAllocator};
+use floof::memory::EmptyFrameAllocator;
 use floof::task::Task;
 use floof::task::executor::Executor;
 use floof::task::keyboard::print_keypresses;
 use floof::{QemuExitCode, Testable, allocator, exit_qemu, memory, print, println, serial_println};
 use floof::vga_buffer::{Color, vga_color};
-use x86_64::VirtAddr;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::{FrameAllocator, Page, PageTable, Translate};
 
@@ -54,13 +53,7 @@ fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     println!("Novyn");
     vga_color(Color::White, Color::Black);
 
-    floof::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    floof::init(boot_info);
 
     // let page = Page::containing_address(VirtAddr::new(0));
     // memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
@@ -83,8 +76,9 @@ fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     //     let phys = mapper.translate_addr(virt);
     //     println!("{virt:?} -> {phys:?}");
     // }
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("Heap initialization failed");
+    allocator::init_heap().expect("Heap initialization failed");
+    log!("{}", memory::memory_map());
+    log!("{}", memory::stats());
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
@@ -0,0 +1,367 @@
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr, instructions::tlb, registers::control::{Cr3, Cr3Flags}, structures::{idt::PageFaultErrorCode, paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB, Translate, page_table::{PageTableEntry, PageTableLevel}}}};
+
+use crate::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, VmmError}, wx};
+
+/// Marks a page shared copy-on-write, one of the bits the CPU leaves to the OS
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// One count per physical frame of how many address spaces map it, zero for frames with a
+/// single owner. Set up on the first clone, stored in frames of its own.
+struct FrameRefs {
+    counts: &'static mut [u16],
+}
+
+static REFS: Mutex<Option<FrameRefs>> = Mutex::new(None);
+/// the kernel window's level 4 entries only have to be created once
+static WINDOW_SHARED: Once<()> = Once::new();
+
+impl FrameRefs {
+    fn new(frames: &mut BitmapFrameAllocator) -> Option<Self> {
+        let len = frames.frame_capacity();
+        let pages = (len * size_of::<u16>()).div_ceil(4096);
+        let start = frames.allocate_contiguous(pages, 1, None)?;
+        let ptr: *mut u16 = memory::phys_to_virt(start.start_address()).as_mut_ptr();
+        let counts = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+        counts.fill(0);
+        Some(Self { counts })
+    }
+
+    fn get(&self, frame: PhysFrame) -> u16 {
+        self.counts[index(frame)]
+    }
+
+    /// one more address space maps `frame`
+    fn share(&mut self, frame: PhysFrame) {
+        let count = &mut self.counts[index(frame)];
+        *count = if *count == 0 { 2 } else { *count + 1 };
+    }
+
+    /// one address space stopped mapping `frame`, returns whether it was the last one
+    fn release(&mut self, frame: PhysFrame) -> bool {
+        let count = &mut self.counts[index(frame)];
+        match *count {
+            0 | 1 => {
+                *count = 0;
+                true
+            }
+            _ => {
+                *count -= 1;
+                false
+            }
+        }
+    }
+}
+
+fn index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / 4096) as usize
+}
+
+/// A set of page tables of its own. The level 4 entries the kernel uses are shared, the
+/// rest is private to the address space and meant for user mappings.
+///
+/// Mappings in the private part use 4 KiB pages, so `clone_cow` can share them page by page.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space that maps the kernel and nothing else
+    pub fn new() -> Result<Self, VmmError> {
+        memory::with_memory(|kernel, frames| {
+            share_kernel_window(kernel, frames)?;
+            let level_4_frame = frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+            let table = table_mut(level_4_frame.start_address());
+            table.zero();
+            for (entry, kernel_entry) in table.iter_mut().zip(kernel.level_4_table().iter()) {
+                if !kernel_entry.is_unused() {
+                    entry.clone_from(kernel_entry);
+                }
+            }
+            Ok(Self { level_4_frame })
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Makes this the address space the CPU translates through.
+    ///
+    /// # Safety
+    ///
+    /// The address space must stay alive until another one is switched to.
+    pub unsafe fn switch_to(&self) {
+        unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+    }
+
+    /// Switches back to the page tables the bootloader set up
+    pub fn switch_to_kernel() {
+        let frame = memory::with_memory(|kernel, _| kernel_level_4_frame(kernel));
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+
+    /// Whether `start..start + size` lies completely outside the shared kernel part
+    pub fn is_private_range(&self, start: VirtAddr, size: u64) -> bool {
+        let Some(last) = size.checked_sub(1).and_then(|last| start.as_u64().checked_add(last)) else {
+            return false;
+        };
+        let last = VirtAddr::new(last);
+        memory::with_memory(|kernel, _| {
+            (u16::from(start.p4_index())..=u16::from(last.p4_index()))
+                .all(|i| kernel.level_4_table()[usize::from(i)].is_unused())
+        })
+    }
+
+    /// Maps fresh zeroed 4 KiB frames to the private range `start..start + size`
+    pub fn map(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+        if !start.is_aligned(4096u64) || !size.is_multiple_of(4096) {
+            return Err(VmmError::Unaligned);
+        }
+        if !self.is_private_range(start, size) {
+            return Err(VmmError::Overlap("kernel"));
+        }
+
+        let level_4_frame = self.level_4_frame;
+        memory::with_memory(|_, frames| {
+            let mut mapper = mapper_for(level_4_frame);
+            let first = Page::<Size4KiB>::containing_address(start);
+            for page in Page::range(first, first + size / 4096) {
+                let frame = frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+                table_mut(frame.start_address()).zero();
+                let flush = unsafe { mapper.map_to(page, frame, wx::restrict(flags | PageTableFlags::PRESENT), frames) }.inspect_err(|_| {
+                    unsafe { frames.deallocate_frame(frame) };
+                })?;
+                if self.is_active() {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// The physical address `addr` is mapped to in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        memory::with_memory(|_, _| mapper_for(self.level_4_frame).translate_addr(addr))
+    }
+
+    /// Creates a copy of this address space that shares every private frame with it.
+    ///
+    /// Writable pages become read-only and copy-on-write in both, the page fault handler gives
+    /// the writing side its own copy of the frame.
+    pub fn clone_cow(&mut self) -> Result<Self, VmmError> {
+        let level_4_frame = self.level_4_frame;
+        let child = Self::new()?;
+
+        let result = memory::with_memory(|kernel, frames| {
+            let mut refs = REFS.lock();
+            if refs.is_none() {
+                *refs = Some(FrameRefs::new(frames).ok_or(VmmError::OutOfMemory)?);
+            }
+            let refs = refs.as_mut().expect("frame refs were just set up");
+
+            let kernel_table = table_mut(kernel_level_4_frame(kernel).start_address());
+            let parent = table_mut(level_4_frame.start_address());
+            let copy = table_mut(child.level_4_frame.start_address());
+            for i in 0..512 {
+                if !is_private(parent, kernel_table, i) {
+                    continue;
+                }
+                clone_table(&parent[i], &mut copy[i], PageTableLevel::Three, frames, refs)?;
+            }
+            Ok(())
+        });
+        // on failure dropping the child frees what was copied so far and drops the shares
+        // the parent's writable pages turned read-only
+        tlb::flush_all();
+        result.map(|()| child)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        memory::with_memory(|kernel, frames| {
+            let mut refs = REFS.lock();
+            let kernel_table = table_mut(kernel_level_4_frame(kernel).start_address());
+            let table = table_mut(self.level_4_frame.start_address());
+            for i in 0..512 {
+                if is_private(table, kernel_table, i) {
+                    free_table(table[i].addr(), PageTableLevel::Three, frames, refs.as_mut());
+                }
+            }
+            unsafe { frames.deallocate_frame(self.level_4_frame) };
+        });
+    }
+}
+
+/// Resolves a write to a copy-on-write page of the active address space by giving it a
+/// frame of its own. Returns false if the fault was something else.
+///
+/// Called by the page fault handler, so it gives up instead of waiting for locks.
+pub fn handle_cow_fault(addr: VirtAddr, err_code: PageFaultErrorCode) -> bool {
+    if !err_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return false;
+    }
+    let level_4_frame = Cr3::read().0;
+
+    memory::try_with_memory(|_, frames| {
+        let Some(entry) = leaf_entry(level_4_frame, addr) else {
+            return false;
+        };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | COW) {
+            return false;
+        }
+        let Some(mut refs) = REFS.try_lock() else {
+            return false;
+        };
+        let Some(refs) = refs.as_mut() else {
+            return false;
+        };
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        let writable = (flags - COW) | PageTableFlags::WRITABLE;
+        if refs.get(frame) <= 1 {
+            // everybody else already made a copy
+            refs.release(frame);
+            entry.set_flags(writable);
+        } else {
+            let Some(copy) = frames.allocate_frame() else {
+                return false;
+            };
+            unsafe {
+                let from: *const u8 = memory::phys_to_virt(frame.start_address()).as_ptr();
+                let to: *mut u8 = memory::phys_to_virt(copy.start_address()).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(from, to, 4096);
+            }
+            refs.release(frame);
+            entry.set_addr(copy.start_address(), writable);
+        }
+        tlb::flush(addr);
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Gives the kernel window of the `vmm` a level 3 table in every slot, so mappings made later
+/// show up in all address spaces that copied the level 4 entries.
+fn share_kernel_window(kernel: &mut OffsetPageTable<'static>, frames: &mut BitmapFrameAllocator) -> Result<(), VmmError> {
+    let mut result = Ok(());
+    WINDOW_SHARED.call_once(|| {
+        let first = VirtAddr::new(vmm::WINDOW_START).p4_index();
+        let last = VirtAddr::new(vmm::WINDOW_END - 1).p4_index();
+        for i in u16::from(first)..=u16::from(last) {
+            let entry = &mut kernel.level_4_table_mut()[PageTableIndex::new(i)];
+            if !entry.is_unused() {
+                continue;
+            }
+            let Some(frame) = frames.allocate_frame() else {
+                result = Err(VmmError::OutOfMemory);
+                return;
+            };
+            table_mut(frame.start_address()).zero();
+            entry.set_addr(frame.start_address(), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    });
+    result
+}
+
+fn kernel_level_4_frame(kernel: &OffsetPageTable<'static>) -> PhysFrame {
+    let table = VirtAddr::from_ptr(kernel.level_4_table() as *const PageTable);
+    PhysFrame::containing_address(memory::virt_to_phys(table))
+}
+
+/// an entry belongs to the address space unless it is the kernel's own
+fn is_private(table: &PageTable, kernel: &PageTable, i: usize) -> bool {
+    !table[i].is_unused() && (kernel[i].is_unused() || kernel[i].addr() != table[i].addr())
+}
+
+fn table_mut(addr: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(addr).as_mut_ptr() }
+}
+
+fn mapper_for(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    unsafe { OffsetPageTable::new(table_mut(level_4_frame.start_address()), memory::physical_memory_offset()) }
+}
+
+/// the level 1 entry mapping `addr`, `None` if there is none or a huge page is in the way
+fn leaf_entry(level_4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = table_mut(level_4_frame.start_address());
+    let mut level = PageTableLevel::Four;
+    while let Some(lower) = level.next_lower_level() {
+        let entry = &table[addr.page_table_index(level)];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = table_mut(entry.addr());
+        level = lower;
+    }
+    Some(&mut table[addr.page_table_index(PageTableLevel::One)])
+}
+
+/// copies the table `entry` points to, of `level`, and everything below it into `into`, sharing
+/// the frames. Every table is linked before it is filled, so whatever was copied when it fails
+/// is freed with the rest of the address space.
+fn clone_table(
+    entry: &PageTableEntry,
+    into: &mut PageTableEntry,
+    level: PageTableLevel,
+    frames: &mut BitmapFrameAllocator,
+    refs: &mut FrameRefs,
+) -> Result<(), VmmError> {
+    let frame = frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+    let copy = table_mut(frame.start_address());
+    copy.zero();
+    into.set_addr(frame.start_address(), entry.flags());
+    let table = table_mut(entry.addr());
+
+    for (entry, copied) in table.iter_mut().zip(copy.iter_mut()) {
+        if entry.is_unused() {
+            continue;
+        }
+        let flags = entry.flags();
+        match level.next_lower_level() {
+            Some(lower) => {
+                assert!(!flags.contains(PageTableFlags::HUGE_PAGE), "huge page in a private mapping");
+                clone_table(entry, copied, lower, frames, refs)?;
+            }
+            None => {
+                let flags = if flags.contains(PageTableFlags::WRITABLE) {
+                    (flags - PageTableFlags::WRITABLE) | COW
+                } else {
+                    flags
+                };
+                entry.set_flags(flags);
+                copied.set_addr(entry.addr(), flags);
+                refs.share(PhysFrame::containing_address(entry.addr()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// frees the table at `addr` of `level`, everything below it, and every frame nobody else maps
+fn free_table(addr: PhysAddr, level: PageTableLevel, frames: &mut BitmapFrameAllocator, mut refs: Option<&mut FrameRefs>) {
+    let table = table_mut(addr);
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let frame = PhysFrame::containing_address(entry.addr());
+        match level.next_lower_level() {
+            Some(lower) => free_table(entry.addr(), lower, frames, refs.as_deref_mut()),
+            None => {
+                let last = refs.as_deref_mut().is_none_or(|refs| refs.release(frame));
+                if last {
+                    unsafe { frames.deallocate_frame(frame) };
+                }
+            }
+        }
+    }
+    unsafe { frames.deallocate_frame(PhysFrame::containing_address(addr)) };
+}
@@ -0,0 +1,233 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB}};
+
+use crate::memory::buddy::{self, BuddyFrameAllocator};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A FrameAllocator that keeps one bit per physical frame, set while the frame is in use.
+///
+/// Frames are handed out by a `BuddyFrameAllocator` owning all free memory, so contiguous
+/// runs and huge frames are found without scanning. The bitmap records what is in use, for
+/// `is_used` and to catch double frees. It lives in the first usable region big enough to
+/// hold it and is accessed through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    buddy: BuddyFrameAllocator,
+    total_frames: usize,
+    used_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the passed memory map is valid, that all frames
+    /// marked as `USABLE` in it are really unused, and that the complete physical memory
+    /// is mapped at `physical_memory_offset`. Only one allocator may be created from the
+    /// same memory map.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let max_addr = usable_regions().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * size_of::<u64>()) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        // steal the start of the first region that can hold the whole bitmap
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_ptr, word_count) };
+        bitmap.fill(!0); // everything is in use until the memory map says otherwise
+
+        let mut allocator = Self {
+            bitmap,
+            buddy: BuddyFrameAllocator::new(physical_memory_offset),
+            total_frames: 0,
+            used_frames: 0,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for idx in start..end {
+                allocator.clear_bit(idx);
+            }
+            allocator.total_frames += end - start;
+        }
+
+        // the frames holding the bitmap are not free anymore
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for idx in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.set_bit(idx);
+        }
+        allocator.used_frames = bitmap_frames as usize;
+
+        // every run of free frames goes to the buddy allocator
+        let mut idx = 0;
+        while let Some(start) = allocator.find_free(idx) {
+            let end = allocator.find_used(start, frame_count).unwrap_or(frame_count);
+            unsafe { allocator.buddy.add_region(frame_addr(start), frame_addr(end)) };
+            idx = end;
+        }
+
+        allocator
+    }
+
+    /// Number of usable frames reported by the memory map
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of usable frames currently handed out, including the ones holding the bitmap
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    /// One past the highest frame number the bitmap covers
+    pub fn frame_capacity(&self) -> usize {
+        self.bitmap.len() * BITS_PER_WORD
+    }
+
+    /// Returns whether the given frame is currently in use (or not usable at all)
+    pub fn is_used(&self, frame: PhysFrame) -> bool {
+        let idx = frame_index(frame);
+        match self.bitmap.get(idx / BITS_PER_WORD) {
+            Some(word) => word & (1 << (idx % BITS_PER_WORD)) != 0,
+            None => true,
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames starting at a multiple of `align` frames,
+    /// all of them below `limit` if one is given.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize, limit: Option<PhysAddr>) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        // buddy blocks are aligned to their size, the frames past `count` are given back
+        let order = buddy::order_for(count).max(buddy::order_for(align));
+        let start = self.buddy.allocate_below(order, limit)?;
+        let first = frame_index(PhysFrame::containing_address(start));
+        unsafe { self.buddy.deallocate_range(frame_addr(first + count), start + (FRAME_SIZE << order)) };
+
+        for idx in first..first + count {
+            self.set_bit(idx);
+        }
+        self.used_frames += count;
+        Some(PhysFrame::containing_address(start))
+    }
+
+    /// Frees frames allocated with `allocate_contiguous`.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have been allocated from this allocator and not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = frame_index(start);
+        for idx in first..first + count {
+            assert!(self.is_used(PhysFrame::containing_address(frame_addr(idx))), "double free of frame {idx:#x}");
+            self.clear_bit(idx);
+        }
+        self.used_frames -= count;
+        unsafe { self.buddy.deallocate_range(frame_addr(first), frame_addr(first + count)) };
+    }
+
+    /// Number of free blocks for every buddy order, index is the order
+    pub fn free_counts(&self) -> [usize; buddy::MAX_ORDER + 1] {
+        self.buddy.free_counts()
+    }
+
+    /// Allocates a naturally aligned frame of any size, e.g. a 2 MiB one for a huge page.
+    ///
+    /// Not a `FrameAllocator` impl, so `allocate_frame` keeps meaning a 4 KiB frame.
+    pub fn allocate_sized<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let count = frames_per::<S>();
+        let start = self.allocate_contiguous(count, count, None)?;
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+
+    /// Frees a frame allocated with `allocate_sized`.
+    ///
+    /// # Safety
+    ///
+    /// The frame must have been allocated from this allocator and not be used anymore.
+    pub unsafe fn deallocate_sized<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(start, frames_per::<S>()) };
+    }
+
+    /// first free frame from `from` on
+    fn find_free(&self, from: usize) -> Option<usize> {
+        let total = self.bitmap.len() * BITS_PER_WORD;
+        (from..total).find(|&idx| self.bitmap[idx / BITS_PER_WORD] & (1 << (idx % BITS_PER_WORD)) == 0)
+    }
+
+    /// first used frame in `from..to`, looking at whole words where possible
+    fn find_used(&self, from: usize, to: usize) -> Option<usize> {
+        let mut idx = from;
+        while idx < to {
+            let word = self.bitmap[idx / BITS_PER_WORD];
+            if idx.is_multiple_of(BITS_PER_WORD) && to - idx >= BITS_PER_WORD {
+                if word != 0 {
+                    return Some(idx + word.trailing_zeros() as usize);
+                }
+                idx += BITS_PER_WORD;
+            } else {
+                if word & (1 << (idx % BITS_PER_WORD)) != 0 {
+                    return Some(idx);
+                }
+                idx += 1;
+            }
+        }
+        None
+    }
+
+    fn set_bit(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] |= 1 << (idx % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] &= !(1 << (idx % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = PhysFrame::containing_address(self.buddy.allocate(0)?);
+        self.set_bit(frame_index(frame));
+        self.used_frames += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        assert!(self.is_used(frame), "double free of frame {frame:?}");
+
+        self.clear_bit(frame_index(frame));
+        self.used_frames -= 1;
+        unsafe { self.buddy.deallocate(frame.start_address(), 0) };
+    }
+}
+
+/// frames of the given size are runs of naturally aligned 4 KiB frames
+const fn frames_per<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE) as usize
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_addr(idx: usize) -> PhysAddr {
+    PhysAddr::new(idx as u64 * FRAME_SIZE)
+}
@@ -0,0 +1,255 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB}};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Blocks range from order 0 (a single 4 KiB frame) to order 18 (1 GiB)
+pub const MAX_ORDER: usize = 18;
+/// The order of a block that exactly covers a 2 MiB frame
+pub const ORDER_2MIB: usize = 9;
+/// The order of a block that exactly covers a 1 GiB frame
+pub const ORDER_1GIB: usize = 18;
+
+/// intrusive free list node, written into the first frame of every free block
+#[repr(C)]
+struct FreeBlock {
+    next: Option<*mut FreeBlock>,
+}
+
+/// A buddy-system physical allocator handing out naturally aligned runs of 2^order frames.
+///
+/// Free blocks are tracked in one intrusive list per order, stored inside the free
+/// frames themselves through the bootloader's physical memory mapping.
+///
+/// The global `BitmapFrameAllocator` hands out every frame through one of these.
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<*mut FreeBlock>; MAX_ORDER + 1],
+    free_counts: [usize; MAX_ORDER + 1],
+    physical_memory_offset: VirtAddr,
+}
+
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator without any memory, see `add_region`
+    pub const fn new(physical_memory_offset: VirtAddr) -> Self {
+        const EMPTY: Option<*mut FreeBlock> = None;
+        Self {
+            free_lists: [EMPTY; MAX_ORDER + 1],
+            free_counts: [0; MAX_ORDER + 1],
+            physical_memory_offset,
+        }
+    }
+
+    /// Create a BuddyFrameAllocator owning every usable region of the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the passed memory map is valid, that all frames
+    /// marked as `USABLE` in it are really unused and not handed out by any other allocator,
+    /// and that the complete physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = Self::new(physical_memory_offset);
+        let usable_regions = memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            unsafe {
+                allocator.add_region(
+                    PhysAddr::new(region.range.start_addr()),
+                    PhysAddr::new(region.range.end_addr()),
+                );
+            }
+        }
+        allocator
+    }
+
+    /// Hands the frames in `start..end` to the allocator, split into the largest aligned blocks.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the range is unused, owned by nobody else, and mapped
+    /// at the physical memory offset.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+
+        while addr < end {
+            let order = largest_order(addr, end);
+            self.push(PhysAddr::new(addr), order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates a naturally aligned block of 2^order frames
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        self.allocate_below(order, None)
+    }
+
+    /// Like `allocate`, with the whole block below `limit` if one is given
+    pub fn allocate_below(&mut self, order: usize, limit: Option<PhysAddr>) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // the block is carved from the start of a larger one, so only that part has to fit
+        let fits = |addr: PhysAddr| limit.is_none_or(|limit| addr + block_size(order) <= limit);
+        let (found, addr) = (order..=MAX_ORDER).find_map(|o| Some((o, self.take(o, fits)?)))?;
+
+        // split the block, giving the upper halves back until it has the requested size
+        for o in (order..found).rev() {
+            self.push(addr + block_size(o), o);
+        }
+
+        Some(addr)
+    }
+
+    /// Frees a block previously returned by `allocate` with the same order, merging it with
+    /// its buddy as long as the buddy is free too.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the block was allocated from this allocator with the
+    /// same order and is not used anymore.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let mut addr = addr.as_u64();
+        let mut order = order;
+
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(PhysAddr::new(buddy), order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(PhysAddr::new(addr), order);
+    }
+
+    /// Frees every frame in `start..end`, split into the largest aligned blocks and merged with
+    /// their buddies. The blocks need not be the ones they were allocated as.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have been allocated from this allocator and not be used anymore.
+    pub unsafe fn deallocate_range(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.as_u64();
+        let end = end.as_u64();
+        while addr < end {
+            let order = largest_order(addr, end);
+            unsafe { self.deallocate(PhysAddr::new(addr), order) };
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates at least `count` physically contiguous 4 KiB frames.
+    ///
+    /// The run is rounded up to the next power of two and must be freed with
+    /// `deallocate_contiguous` using the same count.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let addr = self.allocate(order_for(count))?;
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    /// # Safety
+    ///
+    /// See `deallocate`; `count` must match the one passed to `allocate_contiguous`.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        unsafe { self.deallocate(start.start_address(), order_for(count)) };
+    }
+
+    /// Number of free blocks of the given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_counts[order]
+    }
+
+    /// Number of free blocks for every order, index is the order
+    pub fn free_counts(&self) -> [usize; MAX_ORDER + 1] {
+        self.free_counts
+    }
+
+    /// Total number of free 4 KiB frames over all orders
+    pub fn free_frames(&self) -> usize {
+        self.free_counts
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+
+    fn node_ptr(&self, addr: PhysAddr) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    fn push(&mut self, addr: PhysAddr, order: usize) {
+        let node = self.node_ptr(addr);
+        unsafe { node.write(FreeBlock { next: self.free_lists[order] }) };
+        self.free_lists[order] = Some(node);
+        self.free_counts[order] += 1;
+    }
+
+    /// unlinks the first free block of `order` whose address `fits`
+    fn take(&mut self, order: usize, fits: impl Fn(PhysAddr) -> bool) -> Option<PhysAddr> {
+        let offset = self.physical_memory_offset.as_u64();
+        let mut link: *mut Option<*mut FreeBlock> = &mut self.free_lists[order];
+
+        unsafe {
+            while let Some(node) = *link {
+                let addr = PhysAddr::new(node as u64 - offset);
+                if fits(addr) {
+                    *link = (*node).next;
+                    self.free_counts[order] -= 1;
+                    return Some(addr);
+                }
+                link = &mut (*node).next;
+            }
+        }
+        None
+    }
+
+    /// unlinks the block at `addr` from the free list of `order`, returns false if it is not free
+    fn remove(&mut self, addr: PhysAddr, order: usize) -> bool {
+        self.take(order, |free| free == addr).is_some()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { self.deallocate(frame.start_address(), 0) };
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(ORDER_2MIB).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        unsafe { self.deallocate(frame.start_address(), ORDER_2MIB) };
+    }
+}
+
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// largest order of a block starting at `addr` that is aligned and ends by `end`
+fn largest_order(addr: u64, end: u64) -> usize {
+    let mut order = MAX_ORDER;
+    while !addr.is_multiple_of(block_size(order)) || addr + block_size(order) > end {
+        order -= 1;
+    }
+    order
+}
+
+/// Smallest order whose blocks hold `count` frames
+pub fn order_for(count: usize) -> usize {
+    count.max(1).next_power_of_two().trailing_zeros() as usize
+}
@@ -0,0 +1,166 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::{idt::PageFaultErrorCode, paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError}}};
+
+use crate::memory::{self, huge, wx};
+
+/// How many demand paged regions can be registered at once. The table is looked at from the
+/// page fault handler, so it can't live on the heap.
+pub const MAX_REGIONS: usize = 32;
+
+/// A virtual range whose pages get a zeroed frame on first touch
+#[derive(Debug, Clone, Copy)]
+pub struct DemandRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// exclusive
+    pub end: VirtAddr,
+    /// flags of the pages once they are mapped, `PRESENT` is always added
+    pub flags: PageTableFlags,
+}
+
+impl DemandRegion {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// start or size is not page aligned, or the size is zero
+    Unaligned,
+    /// the range overlaps a registered region
+    Overlap(&'static str),
+    /// all `MAX_REGIONS` slots are taken
+    TableFull,
+}
+
+/// Why a page fault could not be resolved by mapping a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemandFault {
+    /// the address is not inside any registered region
+    NotRegistered,
+    /// the page is present, so the access itself was not allowed
+    ProtectionViolation(&'static str),
+    /// no frame was left to back the page
+    OutOfMemory(&'static str),
+    /// the page table entries in the way could not be used, e.g. a huge page
+    MapFailed(&'static str),
+    /// the region table or the global mapper was locked by the faulting code
+    Busy,
+}
+
+impl fmt::Display for DemandFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DemandFault::NotRegistered => write!(f, "address is not in any demand paged region"),
+            DemandFault::ProtectionViolation(name) => write!(f, "protection violation in region `{name}`"),
+            DemandFault::OutOfMemory(name) => write!(f, "out of frames while backing region `{name}`"),
+            DemandFault::MapFailed(name) => write!(f, "page tables in the way of region `{name}`"),
+            DemandFault::Busy => write!(f, "memory structures were locked when the fault happened"),
+        }
+    }
+}
+
+static REGIONS: Mutex<[Option<DemandRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Registers `size` bytes at `start` to be backed by zeroed frames on first touch.
+///
+/// Nothing is mapped up front, so reserving a large stack or buffer costs no memory until it
+/// is used. The range must not be mapped already.
+pub fn register(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), RegisterError> {
+    if size == 0 || !start.is_aligned(4096u64) || !size.is_multiple_of(4096) {
+        return Err(RegisterError::Unaligned);
+    }
+    let end = start + size;
+
+    let mut regions = REGIONS.lock();
+    if let Some(other) = regions.iter().flatten().find(|r| r.overlaps(start, end)) {
+        return Err(RegisterError::Overlap(other.name));
+    }
+    let slot = regions.iter_mut().find(|slot| slot.is_none()).ok_or(RegisterError::TableFull)?;
+    *slot = Some(DemandRegion { name, start, end, flags: flags | PageTableFlags::PRESENT });
+    Ok(())
+}
+
+/// Removes the region starting at `start`, unmapping every page that was touched and giving
+/// its frame back. Returns the region, or `None` if no region starts there.
+///
+/// # Safety
+///
+/// Nothing may use the memory of the region anymore.
+pub unsafe fn unregister(start: VirtAddr) -> Option<DemandRegion> {
+    let region = {
+        let mut regions = REGIONS.lock();
+        let slot = regions.iter_mut().find(|slot| slot.is_some_and(|r| r.start == start))?;
+        slot.take()?
+    };
+
+    // `vmm::protect` may have merged touched pages into huge pages
+    memory::with_memory(|mapper, frames| huge::unmap_range(mapper, frames, region.start, region.end - region.start, true));
+    Some(region)
+}
+
+/// Changes the flags pages of the region starting at `start` get from now on, pages that are
+/// mapped already keep theirs. Returns false if no region starts there.
+pub fn set_flags(start: VirtAddr, flags: PageTableFlags) -> bool {
+    let mut regions = REGIONS.lock();
+    match regions.iter_mut().flatten().find(|r| r.start == start) {
+        Some(region) => {
+            region.flags = flags | PageTableFlags::PRESENT;
+            true
+        }
+        None => false,
+    }
+}
+
+/// The registered region containing `addr`, if any
+pub fn region_of(addr: VirtAddr) -> Option<DemandRegion> {
+    REGIONS.lock().iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+/// Called by the page fault handler, maps a zeroed frame if `addr` lies in a registered region
+/// and the page is simply not present yet.
+pub fn handle_page_fault(addr: VirtAddr, err_code: PageFaultErrorCode) -> Result<(), DemandFault> {
+    // the faulting code might hold any of these locks, waiting for them would never end
+    let region = REGIONS
+        .try_lock()
+        .ok_or(DemandFault::Busy)?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+        .ok_or(DemandFault::NotRegistered)?;
+
+    if err_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(DemandFault::ProtectionViolation(region.name));
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    memory::try_with_memory(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().ok_or(DemandFault::OutOfMemory(region.name))?;
+        unsafe {
+            let ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+            ptr.write_bytes(0, 4096);
+        }
+
+        match unsafe { mapper.map_to(page, frame, wx::restrict(region.flags), frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                match err {
+                    MapToError::FrameAllocationFailed => Err(DemandFault::OutOfMemory(region.name)),
+                    _ => Err(DemandFault::MapFailed(region.name)),
+                }
+            }
+        }
+    })
+    .ok_or(DemandFault::Busy)?
+}
@@ -0,0 +1,123 @@
+use core::{fmt, slice};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+
+use crate::memory;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The highest physical address a device can reach
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaLimit {
+    /// 64 bit capable devices
+    None,
+    /// 32 bit devices, most PCI cards
+    Below4GiB,
+    /// ISA DMA, the floppy controller and the Sound Blaster
+    Below16MiB,
+}
+
+impl DmaLimit {
+    fn limit(self) -> Option<PhysAddr> {
+        match self {
+            DmaLimit::None => None,
+            DmaLimit::Below4GiB => Some(PhysAddr::new(1 << 32)),
+            DmaLimit::Below16MiB => Some(PhysAddr::new(16 << 20)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    ZeroSize,
+    /// the alignment is not a power of two
+    BadAlignment,
+    /// no contiguous run of free frames is large enough below the limit
+    OutOfMemory,
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::ZeroSize => write!(f, "dma buffers can't be empty"),
+            DmaError::BadAlignment => write!(f, "alignment is not a power of two"),
+            DmaError::OutOfMemory => write!(f, "no contiguous physical memory left"),
+        }
+    }
+}
+
+/// Physically contiguous, zeroed memory a device can read and write, freed on drop.
+///
+/// Accessed through the physical memory mapping, which is write-back cached. That is fine on
+/// x86, where DMA is cache coherent.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates at least `len` bytes starting at a multiple of `align`, rounded up to whole
+    /// frames. Alignments below a page are always met.
+    ///
+    /// The frames come from the buddy allocator behind the global frame allocator, which
+    /// gives the unused rest of a power of two sized block back right away.
+    pub fn allocate(len: usize, align: usize, limit: DmaLimit) -> Result<Self, DmaError> {
+        if len == 0 {
+            return Err(DmaError::ZeroSize);
+        }
+        if !align.is_power_of_two() {
+            return Err(DmaError::BadAlignment);
+        }
+        let count = len.div_ceil(PAGE_SIZE);
+        let align = (align / PAGE_SIZE).max(1);
+
+        let start = memory::with_memory(|_, frames| frames.allocate_contiguous(count, align, limit.limit()))
+            .ok_or(DmaError::OutOfMemory)?;
+        let buffer = Self { phys: start.start_address(), len };
+        unsafe { buffer.virt_addr().as_mut_ptr::<u8>().write_bytes(0, count * PAGE_SIZE) };
+        Ok(buffer)
+    }
+
+    /// The address to give to the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The address the kernel accesses the buffer at
+    pub fn virt_addr(&self) -> VirtAddr {
+        memory::phys_to_virt(self.phys)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.virt_addr().as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    /// The device may write to the buffer behind the compiler's back, so reads of memory it
+    /// owns should go through `as_ptr` and `read_volatile` instead.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let start = PhysFrame::containing_address(self.phys);
+        memory::with_memory(|_, frames| unsafe { frames.deallocate_contiguous(start, self.len.div_ceil(PAGE_SIZE)) });
+    }
+}
@@ -0,0 +1,448 @@
+use core::arch::x86_64::__cpuid;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr, instructions::tlb, structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate, mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult}, page_table::{PageTableEntry, PageTableLevel}}};
+
+use crate::memory::{self, bitmap::BitmapFrameAllocator, wx};
+
+/// The PAT bit of a huge page entry, part of the address field of 4 KiB entries
+const HUGE_PAT: u64 = 1 << 12;
+/// flags the CPU sets on its own, ignored when comparing entries
+const STATUS_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+/// above this many pages the whole TLB is flushed instead of every page on its own
+const FLUSH_ALL_PAGES: u64 = 64;
+
+static GIB_PAGES: Once<bool> = Once::new();
+
+/// Whether the CPU can map 1 GiB pages
+// `__cpuid` is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn gib_pages_supported() -> bool {
+    // CPUID.80000001h:EDX bit 26
+    *GIB_PAGES.call_once(|| unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0)
+}
+
+/// The largest page size `start..start + size` could be mapped with, useful as an alignment
+pub fn best_alignment(size: u64) -> u64 {
+    if size >= Size1GiB::SIZE && gib_pages_supported() {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps fresh frames to `start..start + size`, using the largest pages the alignment allows.
+///
+/// Falls back to smaller pages when no huge frame is left. If a page can't be mapped at all,
+/// everything mapped so far is unmapped again.
+pub fn map_frames(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = wx::restrict(flags | PageTableFlags::PRESENT);
+    let end = start + size;
+    let mut addr = start;
+
+    while addr < end {
+        let remaining = end - addr;
+        let mapped = if fits::<Size1GiB>(addr, remaining) && gib_pages_supported() {
+            map_huge::<Size1GiB>(mapper, frames, addr, flags)
+        } else {
+            None
+        };
+        let mapped = mapped.or_else(|| if fits::<Size2MiB>(addr, remaining) {
+            map_huge::<Size2MiB>(mapper, frames, addr, flags)
+        } else {
+            None
+        });
+
+        match mapped {
+            Some(page_size) => addr += page_size,
+            None => {
+                let result = frames
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)
+                    .and_then(|frame| {
+                        let result = unsafe { mapper.map_to(Page::containing_address(addr), frame, flags, frames) };
+                        if result.is_err() {
+                            unsafe { frames.deallocate_frame(frame) };
+                        }
+                        result
+                    });
+                match result {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        unmap_range(mapper, frames, start, addr - start, true);
+                        return Err(err);
+                    }
+                }
+                addr += Size4KiB::SIZE;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fits<S: PageSize>(addr: VirtAddr, remaining: u64) -> bool {
+    addr.is_aligned(S::SIZE) && remaining >= S::SIZE
+}
+
+/// maps one huge page at `addr`, `None` if there is no frame for it or the tables are in the way
+fn map_huge<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Option<u64>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let frame = frames.allocate_sized::<S>()?;
+    match unsafe { mapper.map_to(Page::<S>::containing_address(addr), frame, flags, frames) } {
+        Ok(flush) => {
+            flush.flush();
+            Some(S::SIZE)
+        }
+        Err(_) => {
+            unsafe { frames.deallocate_sized(frame) };
+            None
+        }
+    }
+}
+
+/// Maps `start..start + size` to the physical range at `phys` with 4 KiB pages
+pub fn map_physical(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    // in 4 KiB entries HUGE_PAGE is the PAT bit, which `map_to` refuses, so it is set afterwards
+    let pat = flags.contains(PageTableFlags::HUGE_PAGE);
+    let flags = wx::restrict((flags - PageTableFlags::HUGE_PAGE) | PageTableFlags::PRESENT);
+    let first = Page::<Size4KiB>::containing_address(start);
+    for (i, page) in Page::range(first, first + size / Size4KiB::SIZE).enumerate() {
+        let frame = PhysFrame::containing_address(phys + i as u64 * Size4KiB::SIZE);
+        match unsafe { mapper.map_to(page, frame, flags, frames) } {
+            Ok(flush) => {
+                if pat && let Some(entry) = entry_mut(mapper, page.start_address(), PageTableLevel::One) {
+                    entry.set_addr(frame.start_address(), flags | PageTableFlags::HUGE_PAGE);
+                }
+                flush.flush();
+            }
+            Err(err) => {
+                unmap_range(mapper, frames, start, i as u64 * Size4KiB::SIZE, false);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps every page in `start..start + size` whatever its size, giving the frames back if
+/// `free` is set. Huge pages only partly inside the range are split first.
+pub fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    free: bool,
+) {
+    let flush_all = size / Size4KiB::SIZE > FLUSH_ALL_PAGES;
+    let end = start + size;
+    let mut addr = start;
+
+    while addr < end {
+        let TranslateResult::Mapped { frame, .. } = mapper.translate(addr) else {
+            addr += Size4KiB::SIZE;
+            continue;
+        };
+
+        let remaining = end - addr;
+        let unmapped = match frame {
+            MappedFrame::Size4KiB(_) => unmap_page::<Size4KiB>(mapper, frames, addr, free, flush_all),
+            MappedFrame::Size2MiB(_) if fits::<Size2MiB>(addr, remaining) => {
+                unmap_page::<Size2MiB>(mapper, frames, addr, free, flush_all)
+            }
+            MappedFrame::Size1GiB(_) if fits::<Size1GiB>(addr, remaining) => {
+                unmap_page::<Size1GiB>(mapper, frames, addr, free, flush_all)
+            }
+            // only part of the huge page goes away, split it and look again
+            MappedFrame::Size2MiB(_) => split(mapper, frames, addr, PageTableLevel::Two).then_some(0),
+            MappedFrame::Size1GiB(_) => split(mapper, frames, addr, PageTableLevel::Three).then_some(0),
+        };
+        // a table that could not be split or unmapped is skipped
+        addr += unmapped.unwrap_or(Size4KiB::SIZE);
+    }
+
+    if flush_all {
+        tlb::flush_all();
+    }
+}
+
+fn unmap_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    free: bool,
+    flush_all: bool,
+) -> Option<u64>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    if S::SIZE == Size4KiB::SIZE {
+        clear_pat(mapper, addr);
+    }
+    let (frame, flush) = mapper.unmap(Page::<S>::containing_address(addr)).ok()?;
+    if flush_all {
+        flush.ignore();
+    } else {
+        flush.flush();
+    }
+    if free {
+        unsafe { frames.deallocate_sized(frame) };
+    }
+    Some(S::SIZE)
+}
+
+/// Sets the flags of every mapped page in `start..start + size`, splitting huge pages that
+/// stick out of the range and merging what ends up uniform again.
+pub fn protect_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let flags = wx::restrict(flags | PageTableFlags::PRESENT);
+    let flush_all = size / Size4KiB::SIZE > FLUSH_ALL_PAGES;
+    let updated = update_range(mapper, frames, start, size, flags, flush_all);
+    // pages changed before an error need the flush just as much
+    if flush_all {
+        tlb::flush_all();
+    }
+    updated?;
+
+    // the blocks at both ends might have become uniform again
+    merge_around(mapper, frames, start, size);
+    Ok(())
+}
+
+fn update_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frames: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    flush_all: bool,
+) -> Result<(), FlagUpdateError> {
+    let end = start + size;
+    let mut addr = start;
+
+    while addr < end {
+        let TranslateResult::Mapped { frame, .. } = mapper.translate(addr) else {
+            addr += Size4KiB::SIZE;
+            continue;
+        };
+
+        let remaining = end - addr;
+        addr += match frame {
+            MappedFrame::Size4KiB(_) => update::<Size4KiB>(mapper, addr, flags, flush_all)?,
+            MappedFrame::Size2MiB(_) if fits::<Size2MiB>(addr, remaining) => {
+                update::<Size2MiB>(mapper, addr, flags, flush_all)?
+            }
+            MappedFrame::Size1GiB(_) if fits::<Size1GiB>(addr, remaining) => {
+                update::<Size1GiB>(mapper, addr, flags, flush_all)?
+            }
+            MappedFrame::Size2MiB(_) => {
+                if !split(mapper, frames, addr, PageTableLevel::Two) {
+                    return Err(FlagUpdateError::ParentEntryHugePage);
+                }
+                0
+            }
+            MappedFrame::Size1GiB(_) => {
+                if !split(mapper, frames, addr, PageTableLevel::Three) {
+                    return Err(FlagUpdateError::ParentEntryHugePage);
+                }
+                0
+            }
+        };
+    }
+    Ok(())
+}
+
+fn update<S: PageSize>(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr, flags: PageTableFlags, flush_all: bool) -> Result<u64, FlagUpdateError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(addr);
+    // keep the PAT bit of 4 KiB entries, it shares its position with HUGE_PAGE
+    let flags = if S::SIZE == Size4KiB::SIZE {
+        flags | (current_flags(mapper, addr) & PageTableFlags::HUGE_PAGE)
+    } else {
+        flags
+    };
+    let flush = unsafe { mapper.update_flags(page, flags)? };
+    if flush_all {
+        flush.ignore();
+    } else {
+        flush.flush();
+    }
+    Ok(S::SIZE)
+}
+
+/// `Mapper<Size4KiB>::unmap` takes a set PAT bit for the huge page bit and refuses the entry,
+/// so it is cleared first. Nothing touches the page before it is gone.
+fn clear_pat(mapper: &mut OffsetPageTable<'static>, addr: VirtAddr) {
+    if let Some(entry) = entry_mut(mapper, addr, PageTableLevel::One)
+        && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            entry.set_flags(entry.flags() - PageTableFlags::HUGE_PAGE);
+        }
+}
+
+fn current_flags(mapper: &OffsetPageTable<'static>, addr: VirtAddr) -> PageTableFlags {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => PageTableFlags::empty(),
+    }
+}
+
+/// tries to merge every 2 MiB and 1 GiB block touching `start..start + size`
+fn merge_around(mapper: &mut OffsetPageTable<'static>, frames: &mut BitmapFrameAllocator, start: VirtAddr, size: u64) {
+    let end = start + size;
+    let mut block = start.align_down(Size2MiB::SIZE);
+    while block < end {
+        merge(mapper, frames, block, PageTableLevel::Two);
+        block += Size2MiB::SIZE;
+    }
+
+    if gib_pages_supported() {
+        let mut block = start.align_down(Size1GiB::SIZE);
+        while block < end {
+            merge(mapper, frames, block, PageTableLevel::Three);
+            block += Size1GiB::SIZE;
+        }
+    }
+}
+
+/// The entry translating `addr` in the table of `level`, without creating missing tables.
+/// `None` if a table on the way is missing or a huge page is found above `level`.
+fn entry_mut<'m>(mapper: &'m mut OffsetPageTable<'static>, addr: VirtAddr, level: PageTableLevel) -> Option<&'m mut PageTableEntry> {
+    let mut table: *mut PageTable = mapper.level_4_table_mut();
+    let mut current = PageTableLevel::Four;
+    while current != level {
+        let entry = unsafe { &(&*table)[addr.page_table_index(current)] };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = memory::phys_to_virt(entry.addr()).as_mut_ptr();
+        current = current.next_lower_level()?;
+    }
+    Some(unsafe { &mut (&mut *table)[addr.page_table_index(level)] })
+}
+
+/// Splits the huge page mapping `addr` at `level` (two for 2 MiB, three for 1 GiB) into a table
+/// of 512 smaller pages mapping the same frames. Returns false if that was not possible.
+pub fn split(mapper: &mut OffsetPageTable<'static>, frames: &mut BitmapFrameAllocator, addr: VirtAddr, level: PageTableLevel) -> bool {
+    let Some(entry) = entry_mut(mapper, addr, level) else {
+        return false;
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) {
+        return false;
+    }
+    let Some(table_frame) = frames.allocate_frame() else {
+        return false;
+    };
+
+    let raw = entry.addr().as_u64();
+    let pat = raw & HUGE_PAT != 0;
+    let base = raw & !HUGE_PAT;
+    let table: &mut PageTable = unsafe { &mut *memory::phys_to_virt(table_frame.start_address()).as_mut_ptr() };
+    table.zero();
+
+    for (i, child) in table.iter_mut().enumerate() {
+        let i = i as u64;
+        match level {
+            // 2 MiB children keep the huge bit and the PAT bit where it is
+            PageTableLevel::Three => child.set_addr(PhysAddr::new(base + i * Size2MiB::SIZE + (raw & HUGE_PAT)), flags),
+            // in 4 KiB entries the PAT bit moves to where HUGE_PAGE was
+            _ => {
+                let mut child_flags = flags - PageTableFlags::HUGE_PAGE;
+                if pat {
+                    child_flags |= PageTableFlags::HUGE_PAGE;
+                }
+                child.set_addr(PhysAddr::new(base + i * Size4KiB::SIZE), child_flags);
+            }
+        }
+    }
+
+    entry.set_addr(table_frame.start_address(), table_flags(flags));
+    tlb::flush_all();
+    true
+}
+
+/// Replaces the table below the entry mapping `addr` at `level` with a single huge page if
+/// all 512 entries map one naturally aligned physical range with the same flags. Returns
+/// whether it did.
+pub fn merge(mapper: &mut OffsetPageTable<'static>, frames: &mut BitmapFrameAllocator, addr: VirtAddr, level: PageTableLevel) -> bool {
+    let Some(entry) = entry_mut(mapper, addr, level) else {
+        return false;
+    };
+    let parent_flags = entry.flags();
+    if !parent_flags.contains(PageTableFlags::PRESENT) || parent_flags.contains(PageTableFlags::HUGE_PAGE) {
+        return false;
+    }
+
+    let (child_size, size) = match level {
+        PageTableLevel::Two => (Size4KiB::SIZE, Size2MiB::SIZE),
+        PageTableLevel::Three => (Size2MiB::SIZE, Size1GiB::SIZE),
+        _ => return false,
+    };
+    let table_frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    let table: &PageTable = unsafe { &*memory::phys_to_virt(table_frame.start_address()).as_ptr() };
+
+    let first = &table[0];
+    let flags = first.flags() - STATUS_FLAGS;
+    let base = first.addr().as_u64();
+    // 2 MiB children must be huge pages, 4 KiB ones must not use the PAT
+    let children_huge = level == PageTableLevel::Three;
+    if !flags.contains(PageTableFlags::PRESENT)
+        || flags.contains(PageTableFlags::HUGE_PAGE) != children_huge
+        || !base.is_multiple_of(size)
+    {
+        return false;
+    }
+    let uniform = table.iter().enumerate().all(|(i, child)| {
+        child.flags() - STATUS_FLAGS == flags && child.addr().as_u64() == base + i as u64 * child_size
+    });
+    if !uniform {
+        return false;
+    }
+
+    // the merged page can't be more permissive than the table entry above it was
+    let mut merged = flags | PageTableFlags::HUGE_PAGE;
+    for restricting in [PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE] {
+        if !parent_flags.contains(restricting) {
+            merged -= restricting;
+        }
+    }
+    merged |= parent_flags & PageTableFlags::NO_EXECUTE;
+
+    entry.set_addr(PhysAddr::new(base), merged);
+    tlb::flush_all();
+    unsafe { frames.deallocate_frame(table_frame) };
+    true
+}
+
+/// flags for an entry pointing to a table, the leaf entries restrict access further
+fn table_flags(leaf: PageTableFlags) -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (leaf & PageTableFlags::USER_ACCESSIBLE)
+}
@@ -0,0 +1,193 @@
+use core::{arch::x86_64::__cpuid, marker::PhantomData, ptr};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr, instructions::tlb, registers::model_specific::Msr, structures::paging::PageTableFlags};
+
+use crate::memory::vmm::{self, VmmError};
+
+const IA32_PAT: u32 = 0x277;
+const PAGE_SIZE: u64 = 4096;
+
+// PAT memory types
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WT: u64 = 0x04;
+const WP: u64 = 0x05;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+/// Entries 0 to 3 keep their power-on values so PCD/PWT mean the same with or without PAT,
+/// entry 4, picked by the PAT bit alone, is write-combining.
+const PAT_VALUE: u64 =
+    WB | WT << 8 | UC_MINUS << 16 | UC << 24 | WC << 32 | WP << 40 | UC_MINUS << 48 | UC << 56;
+
+/// The PAT bit of a 4 KiB page table entry, the same bit marks huge pages in the upper levels
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PAT_SUPPORTED: Once<bool> = Once::new();
+
+/// How the CPU may cache accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    /// uncacheable, but MTRRs may still make it write-combining
+    UncacheableMinus,
+    /// strongly uncacheable, what device registers want
+    Uncacheable,
+    /// writes are buffered and merged, for frame buffers. Falls back to `Uncacheable` without PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// The page table flags selecting this mode
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::UncacheableMinus => PageTableFlags::NO_CACHE,
+            CacheMode::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if pat_supported() => PAT_4KIB,
+            CacheMode::WriteCombining => CacheMode::Uncacheable.flags(),
+        }
+    }
+}
+
+/// Programs the PAT so `CacheMode::WriteCombining` is available, called by `init_global`
+pub(crate) fn init_pat() {
+    if !pat_supported() {
+        return;
+    }
+    unsafe { Msr::new(IA32_PAT).write(PAT_VALUE) };
+    // nothing should be cached under the old attributes
+    tlb::flush_all();
+}
+
+// `__cpuid` is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn pat_supported() -> bool {
+    // CPUID.01h:EDX bit 16
+    *PAT_SUPPORTED.call_once(|| unsafe { __cpuid(1) }.edx & (1 << 16) != 0)
+}
+
+/// Integers a register can be read or written as
+pub trait RegisterValue: Copy + private::Sealed {}
+
+impl RegisterValue for u8 {}
+impl RegisterValue for u16 {}
+impl RegisterValue for u32 {}
+impl RegisterValue for u64 {}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// Device memory mapped through the `vmm`, unmapped on drop.
+///
+/// Every access is volatile and checked against the length the region was mapped with.
+#[derive(Debug)]
+pub struct MmioRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    len: usize,
+    cache_mode: CacheMode,
+}
+
+/// Maps `len` bytes of device memory at `phys` with the given caching.
+///
+/// # Safety
+///
+/// `phys..phys + len` must be device memory or otherwise not owned by the frame allocator, and
+/// accessing it must not have side effects that break memory safety.
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize, cache_mode: CacheMode) -> Result<MmioRegion, VmmError> {
+    if len == 0 {
+        return Err(VmmError::Unaligned);
+    }
+    let first = phys.align_down(PAGE_SIZE);
+    let offset = phys - first;
+    let size = (offset + len as u64).next_multiple_of(PAGE_SIZE);
+
+    let flags = PageTableFlags::WRITABLE | cache_mode.flags();
+    let start = unsafe { vmm::map_physical("mmio", first, size, flags)? };
+    Ok(MmioRegion { phys, virt: start + offset, len, cache_mode })
+}
+
+impl MmioRegion {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn read<T: RegisterValue>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.ptr::<T>(offset)) }
+    }
+
+    /// Panics if the access is out of bounds or misaligned.
+    pub fn write<T: RegisterValue>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.ptr::<T>(offset), value) }
+    }
+
+    /// A handle to a single register at `offset`, checked once here.
+    pub fn register<T: RegisterValue>(&self, offset: usize) -> Register<'_, T> {
+        Register { ptr: self.ptr::<T>(offset), _region: PhantomData }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        let end = offset.checked_add(size_of::<T>());
+        assert!(end.is_some_and(|end| end <= self.len), "mmio access at {offset:#x} out of bounds");
+        let ptr = (self.virt + offset as u64).as_mut_ptr::<T>();
+        assert!(ptr.is_aligned(), "misaligned mmio access at {offset:#x}");
+        ptr
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let start = self.virt.align_down(PAGE_SIZE);
+        unsafe { vmm::free(start).expect("mmio region vanished") };
+    }
+}
+
+/// A register inside an `MmioRegion`
+#[derive(Debug, Clone, Copy)]
+pub struct Register<'r, T> {
+    ptr: *mut T,
+    _region: PhantomData<&'r MmioRegion>,
+}
+
+impl<T: RegisterValue> Register<'_, T> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.ptr) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.ptr, value) }
+    }
+
+    /// Reads the register, lets `f` change the value and writes it back
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
@@ -0,0 +1,244 @@
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod demand;
+pub mod dma;
+pub mod huge;
+pub mod mmio;
+pub mod stack;
+pub mod vmm;
+pub mod walk;
+pub mod wx;
+
+use core::fmt;
+use bootloader::{BootInfo, bootinfo::{MemoryMap, MemoryRegionType}};
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, VirtAddr, registers::control::Cr3, structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB}};
+use crate::{allocator, memory::bitmap::BitmapFrameAllocator};
+
+pub struct EmptyFrameAllocator;
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        None
+    }
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        Self {
+            memory_map,
+            next: 0,
+        }
+    }
+
+    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+pub unsafe fn init(offset: VirtAddr) -> OffsetPageTable<'static> {
+    unsafe {
+        let level_4_table = active_level4_table(offset);
+        OffsetPageTable::new(level_4_table, offset)
+    }
+}
+
+/// The kernel's page table mapper, set up by `init_global`
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The kernel's physical frame allocator, set up by `init_global`
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+/// Where the bootloader mapped the complete physical memory, set up by `init_global`
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+/// The bootloader's map of physical memory, set up by `init_global`
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+/// Sets up the global `MAPPER` and `FRAME_ALLOCATOR` from the boot info, enforces W^X on the
+/// mappings the bootloader made, tells the `vmm`
+/// which parts of its window the bootloader already uses and programs the PAT for `mmio`.
+///
+/// # Safety
+///
+/// Same as `init` and `BitmapFrameAllocator::init`: the boot info must be the one passed by
+/// the bootloader, and this must be called only once and never together with `init`.
+pub unsafe fn init_global(boot_info: &'static BootInfo) {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    wx::enforce(&mut mapper);
+    vmm::init(&mapper);
+    mmio::init_pat();
+    PHYSICAL_MEMORY_OFFSET.call_once(|| phys_mem_offset);
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Panics if `init_global` was not called.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.get().expect("memory::init_global not called")
+}
+
+/// The address a physical address can be accessed at through the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Inverse of `phys_to_virt`, only valid for addresses inside the physical memory mapping
+pub fn virt_to_phys(addr: VirtAddr) -> PhysAddr {
+    PhysAddr::new(addr - physical_memory_offset())
+}
+
+/// Runs `f` with the global mapper and frame allocator locked.
+///
+/// `f` must not allocate on the heap, since growing the heap takes the same locks.
+/// Panics if `init_global` was not called.
+pub fn with_memory<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    f(
+        mapper.as_mut().expect("memory::init_global not called"),
+        frame_allocator.as_mut().expect("memory::init_global not called"),
+    )
+}
+
+/// Like `with_memory`, but returns `None` instead of blocking or panicking when the global
+/// mapper is busy or not set up yet.
+pub fn try_with_memory<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> Option<R> {
+    let mut mapper = MAPPER.try_lock()?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock()?;
+    Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+}
+
+/// The physical memory regions the bootloader reported, as a table of type, range and size.
+///
+/// Panics if `init_global` was not called.
+pub fn memory_map() -> MemoryMapTable {
+    MemoryMapTable(MEMORY_MAP.get().expect("memory::init_global not called"))
+}
+
+/// Prints as one line per region, see `memory_map`
+pub struct MemoryMapTable(&'static MemoryMap);
+
+impl fmt::Display for MemoryMapTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<27} {:<8} type", "range", "size")?;
+        for region in self.0.iter() {
+            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+            write!(f, "\n{:#013x}-{:#013x} {} {:?}", start, end, Bytes(end - start), region.region_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where physical memory went, see `stats`
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// everything in the memory map the hardware does not reserve
+    pub total_ram: u64,
+    /// what the bootloader left for the frame allocator
+    pub usable_ram: u64,
+    /// the loaded segments of the kernel
+    pub kernel_image: u64,
+    /// frames holding the kernel's page tables
+    pub page_tables: u64,
+    /// mapped size of the heap, 0 before `allocator::init_heap`
+    pub heap_size: u64,
+    pub free_frames: usize,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "memory: {} total, {} usable, {} free in {} frames",
+            Bytes(self.total_ram), Bytes(self.usable_ram), Bytes(self.free_frames as u64 * 4096), self.free_frames)?;
+        write!(f, "kernel image {}, page tables {}, heap {}",
+            Bytes(self.kernel_image), Bytes(self.page_tables), Bytes(self.heap_size))
+    }
+}
+
+/// Returns how physical memory is used right now.
+///
+/// Takes the allocator lock and then the global memory locks, so it must not be called while
+/// holding either. Panics if `init_global` was not called.
+pub fn stats() -> MemoryStats {
+    let heap_size = allocator::stats().heap_size as u64;
+    let map = MEMORY_MAP.get().expect("memory::init_global not called");
+    let size_of = |wanted: fn(MemoryRegionType) -> bool| -> u64 {
+        map.iter()
+            .filter(|r| wanted(r.region_type))
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum()
+    };
+    let total_ram = size_of(|t| !matches!(t, MemoryRegionType::Reserved | MemoryRegionType::Empty));
+    let kernel_image = size_of(|t| t == MemoryRegionType::Kernel);
+
+    with_memory(|mapper, frames| MemoryStats {
+        total_ram,
+        usable_ram: frames.total_frames() as u64 * 4096,
+        kernel_image,
+        page_tables: walk::table_frames(mapper) as u64 * 4096,
+        heap_size,
+        free_frames: frames.free_frames(),
+    })
+}
+
+/// a byte count in the largest unit it fills, always 8 characters wide so tables line up
+struct Bytes(u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (value, unit) = match self.0 {
+            bytes if bytes >= 1 << 30 => (bytes >> 30, "GiB"),
+            bytes if bytes >= 1 << 20 => (bytes >> 20, "MiB"),
+            bytes if bytes >= 1 << 10 => (bytes >> 10, "KiB"),
+            bytes => (bytes, "B"),
+        };
+        write!(f, "{:>4} {:<3}", value, unit)
+    }
+}
+
+/// MUST BE CALLED ONLY ONCE
+unsafe fn active_level4_table(offset: VirtAddr) -> &'static mut PageTable {
+    let (l4_frame, _) = Cr3::read();
+    let phys = l4_frame.start_address();
+    let virt = offset + phys.as_u64();
+    let table: *mut PageTable = virt.as_mut_ptr();
+
+    unsafe { &mut *table }
+}
+
+pub fn create_example_mapping(
+    page: Page,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000)); // vga
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let res = unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)
+    };
+    res.expect("map_to failed").flush();
+}
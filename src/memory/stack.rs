@@ -0,0 +1,106 @@
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{Page, PageTableFlags}};
+
+use crate::memory::vmm::{self, VmmError};
+
+/// How many guard-paged stacks can exist at once. Looked at by the fault handlers, so the table
+/// is a static.
+pub const MAX_STACKS: usize = 32;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// guard pages of the live stacks, so faults on them can be told apart from other faults
+static GUARDS: Mutex<[Option<(&'static str, Page)>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// A kernel stack of mapped frames from the `vmm` with an unmapped guard page below it.
+///
+/// Running off the bottom faults on the guard page instead of overwriting whatever lies below,
+/// and the fault handlers report it as an overflow of the stack named here.
+#[derive(Debug)]
+pub struct KernelStack {
+    name: &'static str,
+    guard: Page,
+    top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Allocates a stack of at least `size` bytes, rounded up to whole pages
+    pub fn new(name: &'static str, size: u64) -> Result<Self, VmmError> {
+        let size = size.checked_next_multiple_of(PAGE_SIZE).ok_or(VmmError::OutOfAddressSpace)?;
+        let start = vmm::reserve(name, size + PAGE_SIZE)?;
+        let guard = Page::containing_address(start);
+
+        let registered = {
+            let mut guards = GUARDS.lock();
+            match guards.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some((name, guard));
+                    true
+                }
+                None => false,
+            }
+        };
+
+        let bottom = start + PAGE_SIZE;
+        let mapped = if registered {
+            vmm::map(bottom, size, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        } else {
+            Err(VmmError::TableFull)
+        };
+        if let Err(err) = mapped {
+            unregister(guard);
+            unsafe { vmm::free(start)? };
+            return Err(err);
+        }
+
+        Ok(Self { name, guard, top: bottom + size })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard.start_address() + PAGE_SIZE
+    }
+
+    pub fn guard_page(&self) -> Page {
+        self.guard
+    }
+
+    /// Unmaps the stack and gives its frames back.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may run on the stack anymore, and it must not be installed in the TSS.
+    pub unsafe fn free(self) {
+        unregister(self.guard);
+        unsafe { vmm::free(self.guard.start_address()).expect("kernel stack region vanished") };
+    }
+}
+
+fn unregister(guard: Page) {
+    let mut guards = GUARDS.lock();
+    if let Some(slot) = guards.iter_mut().find(|slot| slot.is_some_and(|(_, page)| page == guard)) {
+        *slot = None;
+    }
+}
+
+/// The name of the stack whose guard page contains `addr`, if any.
+///
+/// Meant for fault handlers, so it gives up instead of waiting when the table is locked.
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let page = Page::containing_address(addr);
+    GUARDS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|(_, guard)| *guard == page)
+        .map(|(name, _)| *name)
+}
@@ -0,0 +1,346 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{OffsetPageTable, Page, PageTableFlags, Size4KiB, mapper::{FlagUpdateError, MapToError}}};
+
+use crate::memory::{self, demand, huge};
+
+/// Start of the higher half window the kernel's dynamic mappings are placed in
+pub const WINDOW_START: u64 = 0xffff_8000_0000_0000;
+/// End of the window, exclusive
+pub const WINDOW_END: u64 = 0xffff_c000_0000_0000;
+/// How many regions can be tracked at once. Heap growth and the page fault handler depend on
+/// the VMM, so the table is a static instead of living on the heap.
+pub const MAX_REGIONS: usize = 64;
+
+const PAGE_SIZE: u64 = 4096;
+/// unmapped space left between regions, so running off the end of one faults
+const GAP: u64 = PAGE_SIZE;
+
+/// What the pages of a region are backed by, which decides what unmapping them does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// frames from the global frame allocator, given back when unmapped
+    Frames,
+    /// fixed physical memory like device registers, never given back
+    Physical,
+    /// zeroed frames mapped on first touch, see `demand`
+    Demand,
+    /// already mapped by the bootloader, the VMM leaves it alone
+    Boot,
+}
+
+/// A range of the kernel's address space handed out by the VMM
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub backing: Backing,
+}
+
+impl Region {
+    /// exclusive
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start.as_u64() < end && start < self.end().as_u64()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// an address, size or alignment is not page aligned, or a size is zero
+    Unaligned,
+    /// no free range of the requested size is left in the window
+    OutOfAddressSpace,
+    /// all `MAX_REGIONS` slots are taken
+    TableFull,
+    /// the range overlaps the named region
+    Overlap(&'static str),
+    /// the range is not inside a single region handed out by the VMM
+    NotReserved,
+    /// the region is not backed the way the operation needs
+    WrongBacking(Backing),
+    /// no frame was left for a page or page table
+    OutOfMemory,
+    /// a page of the range is already mapped
+    AlreadyMapped,
+    /// a page table entry in the way maps a huge page
+    HugePage,
+    /// a page of the range is not mapped
+    NotMapped,
+    /// demand paged regions keep one set of flags, so they can only be protected as a whole
+    PartialDemand,
+}
+
+impl fmt::Display for VmmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmmError::Unaligned => write!(f, "range is not page aligned"),
+            VmmError::OutOfAddressSpace => write!(f, "no free virtual range is big enough"),
+            VmmError::TableFull => write!(f, "too many regions"),
+            VmmError::Overlap(name) => write!(f, "range overlaps region `{name}`"),
+            VmmError::NotReserved => write!(f, "range is not inside a reserved region"),
+            VmmError::WrongBacking(backing) => write!(f, "operation not possible on a {backing:?} region"),
+            VmmError::OutOfMemory => write!(f, "out of physical frames"),
+            VmmError::AlreadyMapped => write!(f, "page is already mapped"),
+            VmmError::HugePage => write!(f, "range is covered by a huge page"),
+            VmmError::NotMapped => write!(f, "page is not mapped"),
+            VmmError::PartialDemand => write!(f, "demand paged regions can only be protected as a whole"),
+        }
+    }
+}
+
+impl From<MapToError<Size4KiB>> for VmmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmmError::OutOfMemory,
+            MapToError::ParentEntryHugePage => VmmError::HugePage,
+            MapToError::PageAlreadyMapped(_) => VmmError::AlreadyMapped,
+        }
+    }
+}
+
+struct Vmm {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+static VMM: Mutex<Vmm> = Mutex::new(Vmm { regions: [None; MAX_REGIONS] });
+
+impl Vmm {
+    fn find(&self, start: VirtAddr) -> Option<&Region> {
+        self.regions.iter().flatten().find(|r| r.start == start)
+    }
+
+    /// the region that covers all of `start..start + size`
+    fn covering(&self, start: VirtAddr, size: u64) -> Result<Region, VmmError> {
+        check_aligned(start, size)?;
+        self.regions
+            .iter()
+            .flatten()
+            .find(|r| r.contains(start) && start + size <= r.end())
+            .copied()
+            .ok_or(VmmError::NotReserved)
+    }
+
+    fn insert(&mut self, region: Region) -> Result<(), VmmError> {
+        let (start, end) = (region.start.as_u64(), region.end().as_u64());
+        if let Some(other) = self.regions.iter().flatten().find(|r| r.overlaps(start, end)) {
+            return Err(VmmError::Overlap(other.name));
+        }
+        let slot = self.regions.iter_mut().find(|slot| slot.is_none()).ok_or(VmmError::TableFull)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Option<Region> {
+        self.regions
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|r| r.start == start))?
+            .take()
+    }
+
+    /// lowest free range of `size` bytes aligned to `align` with a gap on both sides
+    fn find_free(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let candidates = core::iter::once(WINDOW_START)
+            .chain(self.regions.iter().flatten().map(|r| r.end().as_u64().saturating_add(GAP)));
+
+        candidates
+            .filter(|&addr| (WINDOW_START..WINDOW_END).contains(&addr))
+            .filter_map(|addr| {
+                let start = addr.checked_next_multiple_of(align)?;
+                let end = start.checked_add(size)?;
+                let free = end <= WINDOW_END
+                    && !self.regions.iter().flatten().any(|r| r.overlaps(start.saturating_sub(GAP), end.saturating_add(GAP)));
+                free.then_some(start)
+            })
+            .min()
+            .map(VirtAddr::new)
+    }
+
+    fn reserve(&mut self, name: &'static str, size: u64, align: u64, backing: Backing) -> Result<VirtAddr, VmmError> {
+        if size == 0 || !size.is_multiple_of(PAGE_SIZE) || !align.is_power_of_two() || align < PAGE_SIZE {
+            return Err(VmmError::Unaligned);
+        }
+        let start = self.find_free(size, align).ok_or(VmmError::OutOfAddressSpace)?;
+        self.insert(Region { name, start, size, backing })?;
+        Ok(start)
+    }
+}
+
+/// Reserves the parts of the window the bootloader already uses, called by `init_global`
+pub(crate) fn init(mapper: &OffsetPageTable<'static>) {
+    let mut vmm = VMM.lock();
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(WINDOW_START)).p4_index();
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(WINDOW_END - 1)).p4_index();
+
+    for index in u16::from(first)..=u16::from(last) {
+        if mapper.level_4_table()[usize::from(index)].is_unused() {
+            continue;
+        }
+        // a level 4 entry covers 512 GiB, sign extended since the window is in the higher half
+        let start = VirtAddr::new_truncate(u64::from(index) << 39);
+        vmm.insert(Region { name: "boot mapping", start, size: 1 << 39, backing: Backing::Boot })
+            .expect("boot mappings overlap");
+    }
+}
+
+/// Reserves `size` bytes of address space without mapping anything, see `map`
+pub fn reserve(name: &'static str, size: u64) -> Result<VirtAddr, VmmError> {
+    reserve_aligned(name, size, PAGE_SIZE)
+}
+
+/// Like `reserve`, with the start aligned to `align`, a power of two of at least a page
+pub fn reserve_aligned(name: &'static str, size: u64, align: u64) -> Result<VirtAddr, VmmError> {
+    VMM.lock().reserve(name, size, align, Backing::Frames)
+}
+
+/// Reserves `size` bytes and maps all of it to fresh frames, aligned so large allocations
+/// can use huge pages
+pub fn allocate(name: &'static str, size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmmError> {
+    let mut vmm = VMM.lock();
+    let start = vmm.reserve(name, size, huge::best_alignment(size), Backing::Frames)?;
+    let mapped = memory::with_memory(|mapper, frames| huge::map_frames(mapper, frames, start, size, flags));
+    if let Err(err) = mapped {
+        vmm.remove(start);
+        return Err(err.into());
+    }
+    Ok(start)
+}
+
+/// Reserves `size` bytes whose pages get a zeroed frame on first touch, see `demand`
+pub fn allocate_lazy(name: &'static str, size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmmError> {
+    let mut vmm = VMM.lock();
+    let start = vmm.reserve(name, size, PAGE_SIZE, Backing::Demand)?;
+    if demand::register(name, start, size, flags).is_err() {
+        vmm.remove(start);
+        return Err(VmmError::TableFull);
+    }
+    Ok(start)
+}
+
+/// Reserves address space for `size` bytes of physical memory at `phys` and maps it there.
+/// The frames are never given back, which makes this fit for device memory.
+///
+/// # Safety
+///
+/// Mapping the physical range with `flags` must not break memory safety, e.g. by aliasing
+/// frames owned by the frame allocator.
+pub unsafe fn map_physical(name: &'static str, phys: PhysAddr, size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmmError> {
+    if !phys.is_aligned(PAGE_SIZE) {
+        return Err(VmmError::Unaligned);
+    }
+    let mut vmm = VMM.lock();
+    let start = vmm.reserve(name, size, PAGE_SIZE, Backing::Physical)?;
+
+    let mapped = memory::with_memory(|mapper, frames| huge::map_physical(mapper, frames, start, phys, size, flags));
+    if let Err(err) = mapped {
+        vmm.remove(start);
+        return Err(err.into());
+    }
+    Ok(start)
+}
+
+/// Maps fresh frames to `start..start + size`, which must lie in a region from `reserve`.
+/// Parts of the range that are suitably aligned get huge pages.
+pub fn map(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    let vmm = VMM.lock();
+    let region = vmm.covering(start, size)?;
+    if region.backing != Backing::Frames {
+        return Err(VmmError::WrongBacking(region.backing));
+    }
+    memory::with_memory(|mapper, frames| huge::map_frames(mapper, frames, start, size, flags))
+        .map_err(VmmError::from)
+}
+
+/// Unmaps `start..start + size` and gives frames back to the frame allocator unless the
+/// region maps physical memory. Pages that are not mapped are skipped, huge pages sticking
+/// out of the range are split.
+///
+/// # Safety
+///
+/// Nothing may use the unmapped memory anymore.
+pub unsafe fn unmap(start: VirtAddr, size: u64) -> Result<(), VmmError> {
+    let vmm = VMM.lock();
+    let region = vmm.covering(start, size)?;
+    if region.backing == Backing::Boot {
+        return Err(VmmError::WrongBacking(region.backing));
+    }
+    let free = region.backing != Backing::Physical;
+    memory::with_memory(|mapper, frames| huge::unmap_range(mapper, frames, start, size, free));
+    Ok(())
+}
+
+/// Changes the flags of every mapped page in `start..start + size`. Pages of a demand paged
+/// region that were not touched yet get the new flags when they are mapped, which is why such
+/// a region can only be protected as a whole.
+///
+/// Huge pages only partly inside the range are split, and blocks that end up with the same
+/// flags all over are merged back into huge pages.
+pub fn protect(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    let vmm = VMM.lock();
+    let region = vmm.covering(start, size)?;
+    if region.backing == Backing::Boot {
+        return Err(VmmError::WrongBacking(region.backing));
+    }
+    if region.backing == Backing::Demand && (start != region.start || size != region.size) {
+        return Err(VmmError::PartialDemand);
+    }
+
+    memory::with_memory(|mapper, frames| huge::protect_range(mapper, frames, start, size, flags))
+        .map_err(|err| match err {
+            FlagUpdateError::PageNotMapped => VmmError::NotMapped,
+            FlagUpdateError::ParentEntryHugePage => VmmError::HugePage,
+        })?;
+    // only once the mapped pages have them, so a failure leaves the region as it was
+    if region.backing == Backing::Demand {
+        demand::set_flags(region.start, flags);
+    }
+    Ok(())
+}
+
+/// Unmaps a whole region like `unmap` and gives its address space back.
+///
+/// # Safety
+///
+/// Nothing may use the region's memory anymore.
+pub unsafe fn free(start: VirtAddr) -> Result<Region, VmmError> {
+    let mut vmm = VMM.lock();
+    let region = *vmm.find(start).ok_or(VmmError::NotReserved)?;
+    match region.backing {
+        Backing::Boot => return Err(VmmError::WrongBacking(region.backing)),
+        Backing::Demand => {
+            unsafe { demand::unregister(start) };
+        }
+        Backing::Frames | Backing::Physical => {
+            let free = region.backing == Backing::Frames;
+            memory::with_memory(|mapper, frames| huge::unmap_range(mapper, frames, start, region.size, free));
+        }
+    }
+    vmm.remove(start);
+    Ok(region)
+}
+
+/// The region containing `addr`, if any
+pub fn region_of(addr: VirtAddr) -> Option<Region> {
+    VMM.lock().regions.iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+/// Calls `f` for every region, in no particular order. The VMM is locked meanwhile.
+pub fn for_each_region(f: impl FnMut(&Region)) {
+    VMM.lock().regions.iter().flatten().for_each(f);
+}
+
+fn check_aligned(start: VirtAddr, size: u64) -> Result<(), VmmError> {
+    if size == 0 || !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(VmmError::Unaligned);
+    }
+    Ok(())
+}
+
@@ -0,0 +1,143 @@
+use core::{fmt, iter::Peekable};
+use x86_64::{VirtAddr, structures::paging::{OffsetPageTable, PageTable, PageTableFlags, page_table::PageTableLevel}};
+
+use crate::{memory, serial_println};
+
+/// one past the highest address of the 48 bit address space, before sign extension
+const ADDRESS_SPACE_END: u64 = 1 << 48;
+/// flags the CPU sets on its own, ignored when merging ranges
+const STATUS_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// A virtually contiguous range of pages that all have the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub size: u64,
+    /// The flags that apply after combining all levels: `WRITABLE` and `USER_ACCESSIBLE` only
+    /// if every level allows it, `NO_EXECUTE` if any level sets it. `ACCESSED` and `DIRTY` are
+    /// left out, and `HUGE_PAGE` only shows up as the PAT bit of 4 KiB pages.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// The last address of the range, the one after it may not be canonical
+    pub fn last(&self) -> VirtAddr {
+        self.start + (self.size - 1)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr <= self.last()
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}-{:#018x} {:>10} KiB {:?}", self.start, self.last(), self.size / 1024, self.flags)
+    }
+}
+
+/// Every mapped range of `mapper`, in address order
+pub fn mappings<'m>(mapper: &'m OffsetPageTable<'static>) -> Mappings<'m> {
+    let pages = Pages { level_4_table: mapper.level_4_table(), offset: mapper.phys_offset(), next: 0 };
+    Mappings { pages: pages.peekable() }
+}
+
+/// The mapping of `mapper` that contains `addr`, if `addr` is mapped
+pub fn mapping_of(mapper: &OffsetPageTable<'static>, addr: VirtAddr) -> Option<Mapping> {
+    mappings(mapper).find(|mapping| mapping.contains(addr))
+}
+
+/// Number of frames holding the page tables of `mapper`, the level 4 table included
+pub fn table_frames(mapper: &OffsetPageTable<'static>) -> usize {
+    count_tables(mapper.level_4_table(), PageTableLevel::Four, mapper.phys_offset())
+}
+
+fn count_tables(table: &PageTable, level: PageTableLevel, offset: VirtAddr) -> usize {
+    let Some(lower) = level.next_lower_level() else {
+        return 1;
+    };
+    let children = table
+        .iter()
+        .filter(|entry| entry.flags().contains(PageTableFlags::PRESENT) && !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .map(|entry| count_tables(unsafe { &*(offset + entry.addr().as_u64()).as_ptr() }, lower, offset))
+        .sum::<usize>();
+    1 + children
+}
+
+/// Prints every mapped range of the kernel's page tables over serial
+pub fn dump() {
+    memory::with_memory(|mapper, _| {
+        serial_println!("page table mappings:");
+        for mapping in mappings(mapper) {
+            serial_println!("  {}", mapping);
+        }
+    });
+}
+
+/// Iterator returned by `mappings`, merges the pages of the walk into ranges
+pub struct Mappings<'m> {
+    pages: Peekable<Pages<'m>>,
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut mapping = self.pages.next()?;
+        while let Some(next) = self.pages.next_if(|next| next.start.as_u64() == mapping.start.as_u64().wrapping_add(mapping.size) && next.flags == mapping.flags) {
+            mapping.size += next.size;
+        }
+        Some(mapping)
+    }
+}
+
+/// walks the tables one leaf entry at a time, each as its own `Mapping`
+struct Pages<'m> {
+    level_4_table: &'m PageTable,
+    offset: VirtAddr,
+    /// where to continue, without sign extension
+    next: u64,
+}
+
+impl Iterator for Pages<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        'search: while self.next < ADDRESS_SPACE_END {
+            let addr = VirtAddr::new_truncate(self.next);
+            let mut table = self.level_4_table;
+            let mut level = PageTableLevel::Four;
+            // start with everything allowed and let each level take away
+            let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+            loop {
+                let size = 1u64 << (12 + 9 * (level as u64 - 1));
+                let entry = &table[addr.page_table_index(level)];
+                let entry_flags = entry.flags();
+                if !entry_flags.contains(PageTableFlags::PRESENT) {
+                    self.next = (self.next & !(size - 1)) + size;
+                    continue 'search;
+                }
+
+                let restricting = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+                flags = (flags & (entry_flags | !restricting)) | (entry_flags & PageTableFlags::NO_EXECUTE);
+
+                let huge = entry_flags.contains(PageTableFlags::HUGE_PAGE);
+                match level.next_lower_level() {
+                    Some(lower) if !huge => {
+                        table = unsafe { &*(self.offset + entry.addr().as_u64()).as_ptr() };
+                        level = lower;
+                    }
+                    _ => {
+                        let leaf = entry_flags - STATUS_FLAGS - restricting - PageTableFlags::NO_EXECUTE;
+                        let leaf = if level == PageTableLevel::One { leaf } else { leaf - PageTableFlags::HUGE_PAGE };
+                        let start = self.next & !(size - 1);
+                        self.next = start + size;
+                        return Some(Mapping { start: VirtAddr::new_truncate(start), size, flags: flags | leaf });
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,103 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{VirtAddr, instructions::tlb, registers::{control::{Cr0, Cr0Flags}, model_specific::{Efer, EferFlags}}, structures::paging::{OffsetPageTable, PageTable, PageTableFlags, page_table::PageTableLevel}};
+
+// ELF program headers
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PAGE_SIZE: u64 = 4096;
+
+static NXE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    /// the ELF header of the kernel, placed at the start of the first segment by the linker
+    static __ehdr_start: u8;
+}
+
+/// Turns on `EFER.NXE` and walks the active page tables so no kernel mapping is both writable
+/// and executable: the kernel's code segments become read-only, everything else (data, heap,
+/// stacks, the physical memory mapping) non-executable. Called by `init_global`.
+pub(crate) fn enforce(mapper: &mut OffsetPageTable<'static>) {
+    unsafe {
+        Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE);
+        // make read-only pages read-only for the kernel too
+        Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT);
+    }
+    NXE_ENABLED.store(true, Ordering::Relaxed);
+
+    let offset = mapper.phys_offset();
+    walk(mapper.level_4_table_mut(), PageTableLevel::Four, 0, offset);
+    tlb::flush_all();
+}
+
+/// Whether `NO_EXECUTE` may be used in page table entries
+pub fn nx_enabled() -> bool {
+    NXE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// The flags a new kernel mapping gets, writable mappings are never executable
+pub fn restrict(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) && nx_enabled() {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
+}
+
+/// Whether `addr` lies in one of the kernel's executable segments
+pub fn is_kernel_code(addr: VirtAddr) -> bool {
+    code_segments().any(|(start, end)| (start..end).contains(&addr.as_u64()))
+}
+
+/// page aligned ranges of the kernel's executable `PT_LOAD` segments
+fn code_segments() -> impl Iterator<Item = (u64, u64)> {
+    let ehdr = &raw const __ehdr_start;
+    let (phoff, phentsize, phnum) = unsafe {
+        (
+            ehdr.add(0x20).cast::<u64>().read_unaligned(),
+            ehdr.add(0x36).cast::<u16>().read_unaligned(),
+            ehdr.add(0x38).cast::<u16>().read_unaligned(),
+        )
+    };
+    (0..usize::from(phnum)).filter_map(move |i| {
+        let phdr = unsafe { ehdr.add(phoff as usize + i * usize::from(phentsize)) };
+        let (p_type, p_flags, vaddr, memsz) = unsafe {
+            (
+                phdr.cast::<u32>().read_unaligned(),
+                phdr.add(4).cast::<u32>().read_unaligned(),
+                phdr.add(16).cast::<u64>().read_unaligned(),
+                phdr.add(40).cast::<u64>().read_unaligned(),
+            )
+        };
+        (p_type == PT_LOAD && p_flags & PF_X != 0)
+            .then(|| (vaddr / PAGE_SIZE * PAGE_SIZE, (vaddr + memsz).next_multiple_of(PAGE_SIZE)))
+    })
+}
+
+/// fixes the leaf entries of the table at `table`, which maps addresses starting at `base`
+fn walk(table: &mut PageTable, level: PageTableLevel, base: u64, offset: VirtAddr) {
+    let shift = 12 + 9 * (level as u64 - 1);
+    for (i, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = VirtAddr::new_truncate(base | (i as u64) << shift).as_u64();
+        let lower = level.next_lower_level().filter(|_| !flags.contains(PageTableFlags::HUGE_PAGE));
+        match lower {
+            Some(lower) => {
+                let table = unsafe { &mut *(offset + entry.addr().as_u64()).as_mut_ptr() };
+                walk(table, lower, start, offset);
+            }
+            None => {
+                let end = start + (1 << shift);
+                let code = code_segments().any(|(code_start, code_end)| start < code_end && code_start < end);
+                let flags = if code {
+                    flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE
+                } else {
+                    flags | PageTableFlags::NO_EXECUTE
+                };
+                entry.set_flags(flags);
+            }
+        }
+    }
+}
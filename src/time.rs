@@ -0,0 +1,142 @@
+use core::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}, time::Duration};
+use spin::Once;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{apic, interrupts::TIMER_IRQ, irq};
+
+/// The tick rate `floof::init` programs
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/// The PIT's input clock in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// channel 0, low then high byte, mode 2 (rate generator)
+const PIT_CHANNEL0_RATE: u8 = 0b0011_0100;
+/// channel 2, low then high byte, mode 0 (interrupt on terminal count)
+const PIT_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+/// bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+const SPEAKER_PORT: u16 = 0x61;
+const CALIBRATION_MS: u64 = 10;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// summed up tick by tick, so changing the frequency doesn't rescale the time already passed
+static NANOS: AtomicU64 = AtomicU64::new(0);
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static USES_LAPIC: AtomicBool = AtomicBool::new(false);
+/// local APIC timer counts per second, measured once against the PIT
+static LAPIC_RATE: Once<u64> = Once::new();
+
+/// Which timer drives the ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerSource {
+    Pit,
+    LocalApic,
+}
+
+/// Starts ticking at about `frequency` Hz, on the local APIC timer if the APICs are enabled and
+/// on the PIT otherwise. Returns the frequency the timer actually runs at, the divisors only
+/// allow so many.
+///
+/// Can be called again to change the rate, uptime keeps counting from where it was.
+pub fn set_frequency(frequency: u32) -> u32 {
+    assert!(frequency > 0, "the timer can't tick at 0 Hz");
+    without_interrupts(|| {
+        if apic::is_enabled() {
+            let rate = *LAPIC_RATE.call_once(calibrate_lapic);
+            if rate > 0 {
+                return start_lapic(rate, frequency);
+            }
+        }
+        start_pit(frequency)
+    })
+}
+
+fn start_lapic(rate: u64, frequency: u32) -> u32 {
+    let count = (rate / u64::from(frequency)).clamp(1, u64::from(u32::MAX));
+    // the local APIC timer interrupts on the timer vector itself, the PIT would tick twice
+    apic::set_isa_irq_masked(TIMER_IRQ, true);
+    apic::start_timer(count as u32, Some(irq::vector(TIMER_IRQ)));
+    USES_LAPIC.store(true, Ordering::Relaxed);
+    store_period(count * NANOS_PER_SEC / rate, rate / count)
+}
+
+fn start_pit(frequency: u32) -> u32 {
+    // a divisor of 0 would mean 65536
+    let divisor = (PIT_FREQUENCY / u64::from(frequency)).clamp(1, u64::from(u16::MAX));
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL0_RATE);
+        let mut channel0 = Port::<u8>::new(PIT_CHANNEL0);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    if USES_LAPIC.swap(false, Ordering::Relaxed) {
+        apic::stop_timer();
+        if irq::handler_count(TIMER_IRQ) > 0 {
+            apic::route_isa_irq(TIMER_IRQ, irq::vector(TIMER_IRQ));
+        }
+    }
+    store_period(divisor * NANOS_PER_SEC / PIT_FREQUENCY, PIT_FREQUENCY / divisor)
+}
+
+fn store_period(nanos_per_tick: u64, frequency: u64) -> u32 {
+    NANOS_PER_TICK.store(nanos_per_tick, Ordering::Relaxed);
+    FREQUENCY.store(frequency as u32, Ordering::Relaxed);
+    frequency as u32
+}
+
+/// Counts the local APIC timer down while PIT channel 2 runs for `CALIBRATION_MS`. Polls the
+/// channel's output, so it works with interrupts disabled.
+fn calibrate_lapic() -> u64 {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let remaining = unsafe {
+        let saved = speaker.read();
+        // gate low and speaker off while the count is loaded
+        speaker.write(saved & !0b11);
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL2_ONESHOT);
+        let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        apic::start_timer(u32::MAX, None);
+        speaker.write((saved & !0b10) | 0b01);
+        while speaker.read() & 0x20 == 0 {
+            spin_loop();
+        }
+        let remaining = apic::timer_count();
+        apic::stop_timer();
+        speaker.write(saved);
+        remaining
+    };
+    remaining.map_or(0, |remaining| u64::from(u32::MAX - remaining) * 1000 / CALIBRATION_MS)
+}
+
+/// Counts a tick, called by the timer interrupt
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    NANOS.fetch_add(NANOS_PER_TICK.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The tick rate in Hz, 0 before `set_frequency`
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn source() -> TimerSource {
+    if USES_LAPIC.load(Ordering::Relaxed) { TimerSource::LocalApic } else { TimerSource::Pit }
+}
+
+/// Time since the timer started, with the resolution of one tick
+pub fn uptime() -> Duration {
+    Duration::from_nanos(NANOS.load(Ordering::Relaxed))
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, address_space::AddressSpace}};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+const USER: u64 = 0x0000_7000_0000_0000;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_memory(|_, frames| frames.free_frames())
+}
+
+#[test_case]
+fn new_address_space_runs_the_kernel() {
+    let space = AddressSpace::new().unwrap();
+    let value = 67u64;
+    unsafe { space.switch_to() };
+    assert!(space.is_active());
+    // the stack and the kernel image are shared
+    assert_eq!(core::hint::black_box(&value), &67);
+    AddressSpace::switch_to_kernel();
+    assert!(!space.is_active());
+}
+
+#[test_case]
+fn private_mappings_are_not_shared() {
+    let start = VirtAddr::new(USER);
+    let mut space = AddressSpace::new().unwrap();
+    assert!(space.is_private_range(start, 4096));
+    space.map(start, 4096, PageTableFlags::WRITABLE).unwrap();
+    assert!(space.translate(start).is_some());
+    assert!(memory::with_memory(|mapper, _| {
+        use x86_64::structures::paging::Translate;
+        mapper.translate_addr(start)
+    })
+    .is_none());
+}
+
+#[test_case]
+fn clone_copies_on_write() {
+    let start = VirtAddr::new(USER);
+    let ptr: *mut u64 = start.as_mut_ptr();
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(start, 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+
+    unsafe {
+        parent.switch_to();
+        ptr.write_volatile(1);
+    }
+    let child = parent.clone_cow().unwrap();
+    assert_eq!(child.translate(start), parent.translate(start));
+
+    unsafe {
+        child.switch_to();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(2);
+    }
+    assert_ne!(child.translate(start), parent.translate(start));
+    // the untouched page is still shared
+    assert_eq!(child.translate(start + 4096), parent.translate(start + 4096));
+
+    unsafe {
+        parent.switch_to();
+        assert_eq!(ptr.read_volatile(), 1);
+        // the last owner gets the frame back writable
+        ptr.write_volatile(3);
+        assert_eq!(ptr.read_volatile(), 3);
+    }
+    AddressSpace::switch_to_kernel();
+}
+
+#[test_case]
+fn dropping_frees_everything() {
+    let start = VirtAddr::new(USER);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(start, 4 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let free_before = free_frames();
+
+    let child = parent.clone_cow().unwrap();
+    drop(child);
+    assert_eq!(free_frames(), free_before);
+}
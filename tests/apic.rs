@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{apic, hlt_loop, interrupt_stats, interrupts::InterruptIndex, time};
+use x86_64::instructions::{hlt, interrupts};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn apics_replace_the_pics() {
+    // QEMU always emulates both
+    assert!(apic::is_supported());
+    assert!(apic::is_enabled());
+    assert!(apic::local_apic_id().is_some());
+}
+
+#[test_case]
+fn timer_and_keyboard_are_routed() {
+    let timer = apic::isa_redirection(0).unwrap();
+    let keyboard = apic::isa_redirection(1).unwrap();
+    assert_eq!(timer & 0xff, u64::from(InterruptIndex::Timer.as_u8()));
+    assert_eq!(keyboard & 0xff, u64::from(InterruptIndex::Keyboard.as_u8()));
+    // the timer line stays masked, the local APIC timer ticks instead of the PIT
+    assert_ne!(timer & (1 << 16), 0);
+    assert_eq!(keyboard & (1 << 16), 0);
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    assert!(interrupts::are_enabled());
+    let timer = InterruptIndex::Timer.as_u8();
+    let hits = interrupt_stats::snapshot(timer).count;
+    let ticks = time::ticks();
+    // without EOIs only the first tick would arrive, other interrupts may wake us up too
+    for _ in 0..100 {
+        if time::ticks() >= ticks + 5 {
+            break;
+        }
+        hlt();
+    }
+    assert!(time::ticks() >= ticks + 5);
+    assert!(interrupt_stats::snapshot(timer).count >= hits + 5);
+}
+
+#[test_case]
+fn masking_works() {
+    assert!(apic::set_isa_irq_masked(1, true));
+    assert_ne!(apic::isa_redirection(1).unwrap() & (1 << 16), 0);
+    assert!(apic::set_isa_irq_masked(1, false));
+    assert_eq!(apic::isa_redirection(1).unwrap() & (1 << 16), 0);
+}
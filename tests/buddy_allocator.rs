@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::buddy::{BuddyFrameAllocator, ORDER_2MIB}};
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB}};
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(bootinfo.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&bootinfo.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn single_frame_merges_back() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let counts = allocator.free_counts();
+
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames() + 1, counts.iter().enumerate().map(|(o, c)| c << o).sum());
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_counts(), counts);
+}
+
+#[test_case]
+fn huge_frame_is_aligned() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let counts = allocator.free_counts();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_counts(), counts);
+}
+
+#[test_case]
+fn contiguous_runs() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let counts = allocator.free_counts();
+
+    let a = allocator.allocate_contiguous(3).unwrap();
+    let b = allocator.allocate_contiguous(3).unwrap();
+    assert_eq!(a.start_address().as_u64() % (4 * 4096), 0);
+    assert_eq!(b.start_address().as_u64() % (4 * 4096), 0);
+    assert_ne!(a, b);
+
+    unsafe {
+        allocator.deallocate_contiguous(a, 3);
+        allocator.deallocate_contiguous(b, 3);
+    }
+    assert_eq!(allocator.free_counts(), counts);
+}
+
+#[test_case]
+fn order_too_large() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    assert_eq!(allocator.allocate(ORDER_2MIB + 10), None);
+}
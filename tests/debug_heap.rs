@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+use core::{fmt::{self, Write}, panic::PanicInfo};
+use alloc::boxed::Box;
+use bootloader::{BootInfo, entry_point};
+use floof::{QemuExitCode, allocator, exit_qemu, hlt_loop, memory, serial_print, serial_println};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    serial_print!("debug_heap::overflow_is_detected...\t");
+    unsafe { memory::init_global(bootinfo) };
+    allocator::init_heap().expect("Couldn't initialize heap");
+
+    let block = Box::new([0u8; 24]);
+    let ptr = Box::into_raw(block) as *mut u8;
+    unsafe {
+        ptr.add(24).write_volatile(0x42); // one past the end
+        drop(Box::from_raw(ptr as *mut [u8; 24]));
+    }
+
+    panic!("Overflow was not detected");
+}
+
+/// Checks whether what is written starts with `prefix`, the heap can't be trusted here
+struct StartsWith {
+    prefix: &'static str,
+    matched: usize,
+    mismatch: bool,
+}
+
+impl Write for StartsWith {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let rest = &self.prefix[self.matched..];
+        let len = rest.len().min(s.len());
+        if rest.as_bytes()[..len] != s.as_bytes()[..len] {
+            self.mismatch = true;
+        }
+        self.matched += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = StartsWith { prefix: "heap: write past the end", matched: 0, mismatch: false };
+    let _ = write!(message, "{}", info.message());
+    if message.mismatch || message.matched < message.prefix.len() {
+        floof::test_panic_handler(info)
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, demand::{self, RegisterError}}};
+use x86_64::{VirtAddr, structures::paging::{Mapper, Page, PageTableFlags, Size4KiB, Translate}};
+
+const REGION_START: u64 = 0x5555_0000_0000;
+const REGION_SIZE: u64 = 64 * 4096;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_memory(|mapper, _| mapper.translate_addr(addr).is_some())
+}
+
+#[test_case]
+fn pages_are_mapped_on_first_touch() {
+    let start = VirtAddr::new(REGION_START);
+    let flags = PageTableFlags::WRITABLE;
+    demand::register("test buffer", start, REGION_SIZE, flags).unwrap();
+
+    let third_page = start + 2 * 4096u64;
+    assert!(!is_mapped(third_page));
+
+    let ptr: *mut u64 = third_page.as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(67);
+        assert_eq!(ptr.read_volatile(), 67);
+    }
+    assert!(is_mapped(third_page));
+    assert!(!is_mapped(start));
+
+    let region = unsafe { demand::unregister(start) }.unwrap();
+    assert_eq!(region.end, start + REGION_SIZE);
+    memory::with_memory(|mapper, _| {
+        assert!(mapper.translate_page(Page::<Size4KiB>::containing_address(third_page)).is_err());
+    });
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    let start = VirtAddr::new(REGION_START + REGION_SIZE);
+    demand::register("first", start, REGION_SIZE, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(
+        demand::register("second", start + 4096u64, 4096, PageTableFlags::WRITABLE),
+        Err(RegisterError::Overlap("first")),
+    );
+    assert_eq!(
+        demand::register("unaligned", start + REGION_SIZE + 1u64, 4096, PageTableFlags::WRITABLE),
+        Err(RegisterError::Unaligned),
+    );
+    unsafe { demand::unregister(start) };
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, dma::{DmaBuffer, DmaError, DmaLimit}}};
+use x86_64::{PhysAddr, structures::paging::PhysFrame};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_memory(|_, frames| frames.free_frames())
+}
+
+#[test_case]
+fn buffers_are_contiguous_and_zeroed() {
+    let mut buffer = DmaBuffer::allocate(5 * 4096, 4096, DmaLimit::None).unwrap();
+    assert_eq!(memory::phys_to_virt(buffer.phys_addr()), buffer.virt_addr());
+    assert!(buffer.as_slice().iter().all(|&b| b == 0));
+    buffer.as_mut_slice()[5 * 4096 - 1] = 67;
+
+    // every frame is in use and the next frame after the buffer is not part of it
+    let first = PhysFrame::containing_address(buffer.phys_addr());
+    memory::with_memory(|_, frames| {
+        for frame in PhysFrame::range(first, first + 5) {
+            assert!(frames.is_used(frame));
+        }
+    });
+}
+
+#[test_case]
+fn alignment_is_respected() {
+    let buffer = DmaBuffer::allocate(4096, 64 * 1024, DmaLimit::None).unwrap();
+    assert!(buffer.phys_addr().is_aligned(64 * 1024u64));
+    let small = DmaBuffer::allocate(100, 16, DmaLimit::None).unwrap();
+    assert_eq!(small.len(), 100);
+    assert!(small.phys_addr().is_aligned(4096u64));
+}
+
+#[test_case]
+fn limits_are_respected() {
+    let isa = DmaBuffer::allocate(16 * 1024, 4096, DmaLimit::Below16MiB).unwrap();
+    assert!(isa.phys_addr() + 16 * 1024u64 <= PhysAddr::new(16 << 20));
+    let pci = DmaBuffer::allocate(16 * 1024, 4096, DmaLimit::Below4GiB).unwrap();
+    assert!(pci.phys_addr() + 16 * 1024u64 <= PhysAddr::new(1 << 32));
+}
+
+#[test_case]
+fn buffers_are_freed_on_drop() {
+    let before = free_frames();
+    let buffer = DmaBuffer::allocate(3 * 4096 + 1, 4096, DmaLimit::None).unwrap();
+    assert_eq!(free_frames(), before - 4);
+    drop(buffer);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn bad_requests_are_rejected() {
+    assert_eq!(DmaBuffer::allocate(0, 4096, DmaLimit::None).unwrap_err(), DmaError::ZeroSize);
+    assert_eq!(DmaBuffer::allocate(4096, 3, DmaLimit::None).unwrap_err(), DmaError::BadAlignment);
+}
+
+#[test_case]
+fn freed_buffers_merge_back() {
+    let counts = memory::with_memory(|_, frames| frames.free_counts());
+    let buffer = DmaBuffer::allocate(3 * 4096, 16 * 1024, DmaLimit::Below16MiB).unwrap();
+    assert!(buffer.phys_addr().is_aligned(16 * 1024u64));
+    drop(buffer);
+    assert_eq!(memory::with_memory(|_, frames| frames.free_counts()), counts);
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use alloc::string::ToString;
+use bootloader::{BootInfo, entry_point};
+use floof::{allocator, exceptions::{self, ErrorCode, Policy, Registers}, hlt_loop};
+use x86_64::structures::idt::{DescriptorTable, ExceptionVector};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn gp_selector_is_decoded() {
+    // index 5 of the IDT, pushed during an external event
+    let code = ErrorCode::decode(ExceptionVector::GeneralProtection, 5 << 3 | 0b011);
+    let ErrorCode::Selector { selector, .. } = code else {
+        panic!("{:?} is not a selector", code);
+    };
+    assert_eq!(selector.index(), 5);
+    assert_eq!(selector.descriptor_table(), DescriptorTable::Idt);
+    assert!(selector.external());
+    assert_eq!(code.to_string(), "0x2b: index 5 in the Idt, during delivery of an external event");
+}
+
+#[test_case]
+fn zero_selector_is_explained() {
+    let code = ErrorCode::decode(ExceptionVector::GeneralProtection, 0);
+    assert_eq!(code.to_string(), "0 (not caused by a selector)");
+}
+
+#[test_case]
+fn other_codes_are_decoded() {
+    let cp = ErrorCode::decode(ExceptionVector::ControlProtection, 1);
+    assert_eq!(cp.to_string(), "0x1: near ret");
+    assert_eq!(ErrorCode::decode(ExceptionVector::AlignmentCheck, 0), ErrorCode::Raw(0));
+}
+
+#[test_case]
+fn faults_can_not_resume() {
+    assert_eq!(exceptions::policy(ExceptionVector::InvalidOpcode), Policy::Panic);
+    assert!(!exceptions::set_policy(ExceptionVector::InvalidOpcode, Policy::Resume));
+    assert_eq!(exceptions::policy(ExceptionVector::InvalidOpcode), Policy::Panic);
+
+    assert!(exceptions::set_policy(ExceptionVector::InvalidOpcode, Policy::Halt));
+    assert_eq!(exceptions::policy(ExceptionVector::InvalidOpcode), Policy::Halt);
+    assert!(exceptions::set_policy(ExceptionVector::InvalidOpcode, Policy::Panic));
+}
+
+#[test_case]
+fn breakpoints_resume() {
+    assert_eq!(exceptions::policy(ExceptionVector::Breakpoint), Policy::Resume);
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn registers_survive_a_resumed_exception() {
+    let (rax, r12): (u64, u64);
+    unsafe { core::arch::asm!("mov rax, 0x4242", "mov r12, 0x6767", "int3", out("rax") rax, out("r12") r12) };
+    assert_eq!((rax, r12), (0x4242, 0x6767));
+}
+
+#[test_case]
+fn registers_are_printed() {
+    let registers = Registers {
+        r15: 15, r14: 14, r13: 13, r12: 12, r11: 11, r10: 10, r9: 9, r8: 8,
+        rbp: 0x7, rdi: 0x6, rsi: 0x5, rdx: 0x4, rcx: 0x3, rbx: 0x2, rax: 0x1,
+    };
+    let printed = registers.to_string();
+    assert!(printed.starts_with("RAX: 0x0000000000000001  RBX: 0x0000000000000002"));
+    assert!(printed.ends_with("R15: 0x000000000000000f"));
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use floof::{allocator, hlt_loop, memory};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    unsafe { memory::init_global(bootinfo) };
+    allocator::init_heap().expect("Couldn't initialize heap");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn empty_slabs_are_returned() {
+    let before = allocator::stats();
+    let boxes: Vec<Box<u64>> = (0..10_000).map(Box::new).collect();
+    let during = allocator::stats();
+    drop(boxes);
+    let after = allocator::stats();
+
+    // at most one empty slab of 16 byte blocks stays cached
+    assert!(after.free_blocks[0] <= before.free_blocks[0] + 4096 / 16);
+    assert!(after.fallback_free >= during.fallback_free + 9_000 * 16);
+}
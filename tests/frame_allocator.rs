@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::bitmap::BitmapFrameAllocator};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame}};
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(bootinfo.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&bootinfo.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn counts_add_up() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    assert!(allocator.total_frames() > 0);
+    assert_eq!(allocator.used_frames() + allocator.free_frames(), allocator.total_frames());
+
+    let used = allocator.used_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert!(allocator.is_used(frame));
+    assert_eq!(allocator.used_frames(), used + 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(!allocator.is_used(frame));
+    assert_eq!(allocator.used_frames(), used);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let a = allocator.allocate_frame().unwrap();
+    let b = allocator.allocate_frame().unwrap();
+    assert_ne!(a, b);
+
+    unsafe { allocator.deallocate_frame(a) };
+    assert_eq!(allocator.allocate_frame(), Some(a));
+
+    unsafe {
+        allocator.deallocate_frame(a);
+        allocator.deallocate_frame(b);
+    }
+}
+
+#[test_case]
+fn many_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+    let first = allocator.allocate_frame().unwrap();
+    for _ in 0..1000 {
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    unsafe { allocator.deallocate_frame(first) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn free_frames_are_in_buddy_blocks() {
+    let guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_ref().unwrap();
+    let in_blocks: usize = allocator.free_counts().iter().enumerate().map(|(order, count)| count << order).sum();
+    assert_eq!(in_blocks, allocator.free_frames());
+}
+
+#[test_case]
+fn contiguous_runs_merge_back() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let counts = allocator.free_counts();
+
+    // not a power of two, the rest of the block goes back right away
+    let run = allocator.allocate_contiguous(5, 4, Some(PhysAddr::new(16 << 20))).unwrap();
+    assert!(run.start_address().is_aligned(4 * 4096u64));
+    assert!(run.start_address() + 5 * 4096u64 <= PhysAddr::new(16 << 20));
+    for frame in PhysFrame::range(run, run + 5) {
+        assert!(allocator.is_used(frame));
+    }
+    assert!(!allocator.is_used(run + 5));
+
+    unsafe { allocator.deallocate_contiguous(run, 5) };
+    assert_eq!(allocator.free_counts(), counts);
+}
@@ -7,19 +7,14 @@
 extern crate alloc;
 #[allow(unused_imports)]
 use core::panic::PanicInfo;
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{BootInfo, entry_point};
-use floof::{allocator::{self, HEAP_SIZE}, hlt_loop, memory::{self, BootInfoFrameAllocator}};
-use x86_64::VirtAddr;
+use floof::{allocator::{self, HEAP_SIZE, leak_tracker}, hlt_loop, memory};
 
 entry_point!(main);
 fn main(bootinfo: &'static BootInfo) -> ! {
-    let phys_mem_offset = bootinfo.physical_memory_offset;
-    let mut mapper = unsafe { memory::init(VirtAddr::new(phys_mem_offset)) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&bootinfo.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Couldn't initialize heap");
+    unsafe { memory::init_global(bootinfo) };
+    allocator::init_heap().expect("Couldn't initialize heap");
 
     test_main();
     hlt_loop();
@@ -66,3 +61,63 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1);
 }
 
+
+#[test_case]
+fn grows_past_initial_size() {
+    let big = vec![1u8; 4 * HEAP_SIZE];
+    assert_eq!(big.iter().map(|&b| b as usize).sum::<usize>(), 4 * HEAP_SIZE);
+}
+
+#[test_case]
+fn many_large_live_allocations() {
+    let mut blocks = Vec::new();
+    for i in 0..16 {
+        blocks.push(vec![i as u8; HEAP_SIZE]);
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(block.iter().all(|&b| b == i as u8));
+    }
+}
+
+#[test_case]
+fn stats_track_usage() {
+    let before = allocator::stats();
+    let big = vec![0u8; 8192];
+    let small = Box::new(1u64);
+
+    let during = allocator::stats();
+    if cfg!(feature = "debug_heap") {
+        // the counts include headers and redzones
+        assert!(during.bytes_in_use >= before.bytes_in_use + 8192 + 8);
+    } else {
+        assert_eq!(during.bytes_in_use, before.bytes_in_use + 8192 + 8);
+    }
+    assert_eq!(during.allocations, before.allocations + 2);
+    assert!(during.high_water_mark >= during.bytes_in_use);
+    assert!(during.largest_free_hole <= during.fallback_free);
+
+    drop(big);
+    drop(small);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    if cfg!(feature = "alloc_fixed_size") {
+        assert!(after.free_blocks.iter().sum::<usize>() > 0);
+    }
+}
+
+#[test_case]
+fn leaks_are_found() {
+    leak_tracker::assert_no_leaks(|| {
+        let x = Box::new(1);
+        let mut v = vec![1, 2, 3];
+        v.push(4);
+        assert_eq!(*x + v.len(), 5);
+    });
+
+    let snapshot = leak_tracker::snapshot();
+    let leaked = Box::leak(Box::new(67u64));
+    assert_eq!(*leaked, 67);
+    let expected = if cfg!(feature = "leak_tracker") { 1 } else { 0 };
+    assert_eq!(snapshot.leak_count(), expected);
+    assert_eq!(snapshot.leaked_bytes(), expected * 8);
+}
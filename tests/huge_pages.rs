@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, vmm}};
+use x86_64::{VirtAddr, structures::paging::{PageTableFlags, Size2MiB, Translate, mapper::{MappedFrame, TranslateResult}}};
+
+const MIB: u64 = 1024 * 1024;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn mapping(addr: VirtAddr) -> Option<(MappedFrame, PageTableFlags)> {
+    memory::with_memory(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, flags, .. } => Some((frame, flags)),
+        _ => None,
+    })
+}
+
+fn is_2mib(addr: VirtAddr) -> bool {
+    matches!(mapping(addr), Some((MappedFrame::Size2MiB(_), _)))
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    let frame = memory::with_memory(|_, frames| frames.allocate_sized::<Size2MiB>()).unwrap();
+    assert!(frame.start_address().is_aligned(2 * MIB));
+    memory::with_memory(|_, frames| unsafe { frames.deallocate_sized(frame) });
+}
+
+#[test_case]
+fn large_allocations_use_huge_pages() {
+    let free_before = memory::with_memory(|_, frames| frames.free_frames());
+    let start = vmm::allocate("huge", 4 * MIB, PageTableFlags::WRITABLE).unwrap();
+    assert!(start.is_aligned(2 * MIB));
+    assert!(is_2mib(start));
+    assert!(is_2mib(start + 2 * MIB));
+
+    let ptr: *mut u64 = (start + 4 * MIB - 8).as_mut_ptr();
+    unsafe { ptr.write_volatile(67) };
+
+    unsafe { vmm::free(start).unwrap() };
+    assert!(mapping(start).is_none());
+    // at most a few page tables stay around
+    let free_after = memory::with_memory(|_, frames| frames.free_frames());
+    assert!(free_after + 4 >= free_before);
+}
+
+#[test_case]
+fn protect_splits_and_merges() {
+    let start = vmm::allocate("split", 2 * MIB, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = (start + MIB).as_mut_ptr();
+    unsafe { ptr.write_volatile(67) };
+
+    // one page in the middle becomes read only, the rest stays writable
+    vmm::protect(start + MIB, 4096, PageTableFlags::empty()).unwrap();
+    let (frame, flags) = mapping(start + MIB).unwrap();
+    assert!(matches!(frame, MappedFrame::Size4KiB(_)));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(mapping(start).unwrap().1.contains(PageTableFlags::WRITABLE));
+    assert_eq!(unsafe { ptr.read_volatile() }, 67);
+
+    // uniform again, so it goes back to a single huge page
+    vmm::protect(start + MIB, 4096, PageTableFlags::WRITABLE).unwrap();
+    assert!(is_2mib(start));
+    assert_eq!(unsafe { ptr.read_volatile() }, 67);
+
+    // unmapping part of it splits it too
+    unsafe { vmm::unmap(start, 4096).unwrap() };
+    assert!(mapping(start).is_none());
+    assert!(mapping(start + 4096u64).is_some());
+    unsafe { vmm::free(start).unwrap() };
+}
+
+#[test_case]
+fn freeing_a_protected_lazy_region_frees_its_frames() {
+    let free_before = memory::with_memory(|_, frames| frames.free_frames());
+    let start = vmm::allocate_lazy("lazy huge", 2 * MIB, PageTableFlags::WRITABLE).unwrap();
+    for offset in (0..2 * MIB).step_by(4096) {
+        let ptr: *mut u64 = (start + offset).as_mut_ptr();
+        unsafe { ptr.write_volatile(67) };
+    }
+
+    // the touched pages may be merged into a huge page here
+    vmm::protect(start, 2 * MIB, PageTableFlags::empty()).unwrap();
+    unsafe { vmm::free(start).unwrap() };
+    assert!(mapping(start).is_none());
+    let free_after = memory::with_memory(|_, frames| frames.free_frames());
+    assert!(free_after + 4 >= free_before);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, interrupt_stats, interrupts::InterruptIndex, serial_println};
+use x86_64::instructions::{hlt, interrupts::int3};
+
+const BREAKPOINT: u8 = 3;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn exceptions_are_counted() {
+    let before = interrupt_stats::snapshot(BREAKPOINT).count;
+    int3();
+    int3();
+    assert_eq!(interrupt_stats::snapshot(BREAKPOINT).count, before + 2);
+}
+
+#[test_case]
+fn timer_is_counted() {
+    let timer = InterruptIndex::Timer.as_u8();
+    let before = interrupt_stats::snapshot(timer).count;
+    for _ in 0..3 {
+        hlt();
+    }
+    assert!(interrupt_stats::snapshot(timer).count >= before + 3);
+}
+
+#[test_case]
+fn latency_is_measured_when_asked() {
+    interrupt_stats::reset();
+    int3();
+    assert_eq!(interrupt_stats::snapshot(BREAKPOINT).cycles, None);
+
+    interrupt_stats::set_timing(true);
+    int3();
+    int3();
+    interrupt_stats::set_timing(false);
+
+    let stats = interrupt_stats::snapshot(BREAKPOINT);
+    assert_eq!(stats.count, 3);
+    let cycles = stats.cycles.unwrap();
+    assert_eq!(cycles.timed, 2);
+    assert!(cycles.min > 0 && cycles.min <= cycles.avg && cycles.avg <= cycles.max);
+}
+
+#[test_case]
+fn snapshot_lists_hit_vectors() {
+    interrupt_stats::reset();
+    int3();
+    for stats in interrupt_stats::snapshot_all() {
+        serial_println!("{}", stats);
+        assert!(stats.count > 0);
+    }
+    assert!(interrupt_stats::snapshot_all().any(|stats| stats.vector == BREAKPOINT));
+}
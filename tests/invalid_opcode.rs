@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use core::{fmt::{self, Write}, panic::PanicInfo};
+use bootloader::{BootInfo, entry_point};
+use floof::{QemuExitCode, exit_qemu, hlt_loop, serial_print, serial_println};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("invalid_opcode::invalid_opcode...\t");
+    floof::init(boot_info);
+    unsafe { core::arch::asm!("ud2") };
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after an invalid opcode");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+/// keeps the start of the panic message, there is no heap to format into
+struct Message {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { bytes: [0; 128], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    if message.starts_with("EXCEPTION: INVALID OPCODE") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Error: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, interrupts::TIMER_IRQ, irq::{self, IrqError, MAX_SHARED}};
+use x86_64::instructions::hlt;
+
+// nothing is attached to these in QEMU
+const FREE_IRQ: u8 = 5;
+const OTHER_FREE_IRQ: u8 = 10;
+
+static FIRST: AtomicUsize = AtomicUsize::new(0);
+static SECOND: AtomicUsize = AtomicUsize::new(0);
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn first(_irq: u8) {
+    FIRST.fetch_add(1, Ordering::Relaxed);
+}
+
+fn second(irq: u8) {
+    assert_eq!(irq, FREE_IRQ);
+    SECOND.fetch_add(1, Ordering::Relaxed);
+}
+
+fn tick(_irq: u8) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn built_in_handlers_are_registered() {
+    assert_eq!(irq::handler_count(TIMER_IRQ), 1);
+    assert_eq!(irq::handler_count(1), 1);
+}
+
+#[test_case]
+fn shared_handlers_all_run() {
+    let a = irq::register_irq(FREE_IRQ, first).unwrap();
+    let b = irq::register_irq(FREE_IRQ, second).unwrap();
+    assert_eq!(irq::handler_count(FREE_IRQ), 2);
+
+    // vector 32 + FREE_IRQ
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+    assert_eq!(SECOND.load(Ordering::Relaxed), 1);
+
+    assert!(irq::unregister_irq(a));
+    assert!(!irq::unregister_irq(a));
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(FIRST.load(Ordering::Relaxed), 1);
+    assert_eq!(SECOND.load(Ordering::Relaxed), 2);
+
+    assert!(irq::unregister_irq(b));
+    assert_eq!(irq::handler_count(FREE_IRQ), 0);
+}
+
+#[test_case]
+fn stale_handles_leave_reused_slots_alone() {
+    let a = irq::register_irq(FREE_IRQ, first).unwrap();
+    assert!(irq::unregister_irq(a));
+    // takes the slot `a` had
+    let b = irq::register_irq(FREE_IRQ, second).unwrap();
+
+    assert!(!irq::unregister_irq(a));
+    assert_eq!(irq::handler_count(FREE_IRQ), 1);
+    let before = SECOND.load(Ordering::Relaxed);
+    unsafe { core::arch::asm!("int 37") };
+    assert_eq!(SECOND.load(Ordering::Relaxed), before + 1);
+
+    assert!(irq::unregister_irq(b));
+    assert_eq!(irq::handler_count(FREE_IRQ), 0);
+}
+
+#[test_case]
+fn lines_have_limits() {
+    assert_eq!(irq::register_irq(16, first), Err(IrqError::NoSuchLine(16)));
+
+    let mut handles = [None; MAX_SHARED];
+    for handle in &mut handles {
+        *handle = Some(irq::register_irq(OTHER_FREE_IRQ, first).unwrap());
+    }
+    assert_eq!(irq::register_irq(OTHER_FREE_IRQ, first), Err(IrqError::LineFull(OTHER_FREE_IRQ)));
+    for handle in handles.into_iter().flatten() {
+        assert!(irq::unregister_irq(handle));
+    }
+}
+
+#[test_case]
+fn drivers_can_share_the_timer() {
+    let handle = irq::register_irq(TIMER_IRQ, tick).unwrap();
+    // the EOI is sent for us, or the second tick would never come
+    for _ in 0..3 {
+        hlt();
+    }
+    assert!(TICKS.load(Ordering::Relaxed) >= 2);
+    assert!(irq::unregister_irq(handle));
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, stack::{self, KernelStack}}};
+use x86_64::structures::paging::Translate;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn stack_has_guard_page() {
+    let stack = KernelStack::new("test stack", 3 * 4096).unwrap();
+    let guard = stack.guard_page().start_address();
+    assert_eq!(stack.bottom(), guard + 4096u64);
+    assert_eq!(stack.top(), stack.bottom() + 3 * 4096u64);
+
+    memory::with_memory(|mapper, _| {
+        assert!(mapper.translate_addr(guard).is_none());
+        assert!(mapper.translate_addr(stack.bottom()).is_some());
+    });
+
+    let ptr: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+    unsafe { ptr.write_volatile(67) };
+
+    assert_eq!(stack::overflowed_stack(guard + 100u64), Some("test stack"));
+    assert_eq!(stack::overflowed_stack(stack.bottom()), None);
+
+    unsafe { stack.free() };
+    assert_eq!(stack::overflowed_stack(guard), None);
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, fmt::{self, Write}, panic::PanicInfo};
+use bootloader::{BootInfo, entry_point};
+use floof::{QemuExitCode, exit_qemu, hlt_loop, memory::stack::KernelStack, serial_print, serial_println};
+use volatile::Volatile;
+
+const EXPECTED: &str = "EXCEPTION: DOUBLE FAULT\nstack overflow in `overflowing stack`";
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("kernel_stack_overflow::overflow_names_the_stack...\t");
+    // the real handlers this time, they have to find the stack by its guard page
+    floof::init(boot_info);
+
+    let stack = KernelStack::new("overflowing stack", 4 * 4096).expect("allocating the stack failed");
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) stack.top().as_u64(),
+            overflow = sym overflow,
+            options(noreturn),
+        );
+    }
+}
+
+extern "C" fn overflow() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    Volatile::new(0).read();
+}
+
+/// Checks whether what is written starts with `prefix`, without allocating
+struct StartsWith {
+    prefix: &'static str,
+    matched: usize,
+    mismatch: bool,
+}
+
+impl Write for StartsWith {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let rest = &self.prefix[self.matched..];
+        let len = rest.len().min(s.len());
+        if rest.as_bytes()[..len] != s.as_bytes()[..len] {
+            self.mismatch = true;
+        }
+        self.matched += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = StartsWith { prefix: EXPECTED, matched: 0, mismatch: false };
+    let _ = write!(message, "{}", info.message());
+    if message.mismatch || message.matched < EXPECTED.len() {
+        floof::test_panic_handler(info)
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
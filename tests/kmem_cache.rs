@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use floof::{allocator::{self, kmem_cache::KmemCache}, hlt_loop, memory};
+
+#[derive(Debug, PartialEq)]
+struct Descriptor {
+    id: u64,
+    flags: u32,
+}
+
+#[repr(align(64))]
+struct Aligned([u8; 100]);
+
+static DESCRIPTORS: KmemCache<Descriptor> =
+    KmemCache::with_constructor("descriptor", || Descriptor { id: 67, flags: 0 });
+static ALIGNED: KmemCache<Aligned> = KmemCache::new("aligned");
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    unsafe { memory::init_global(bootinfo) };
+    allocator::init_heap().expect("Couldn't initialize heap");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn alloc_and_free() {
+    let a = DESCRIPTORS.alloc(Descriptor { id: 1, flags: 2 }).unwrap();
+    let b = DESCRIPTORS.alloc_constructed().unwrap();
+    assert_eq!(*a, Descriptor { id: 1, flags: 2 });
+    assert_eq!(b.id, 67);
+    assert_eq!(DESCRIPTORS.stats().objects_in_use, 2);
+
+    drop(a);
+    drop(b);
+    assert_eq!(DESCRIPTORS.stats().objects_in_use, 0);
+}
+
+#[test_case]
+fn objects_are_aligned() {
+    let objects: Vec<_> = (0..10).map(|_| ALIGNED.alloc(Aligned([0; 100])).unwrap()).collect();
+    for object in &objects {
+        assert_eq!(&**object as *const Aligned as usize % 64, 0);
+        assert_eq!(object.0[99], 0);
+    }
+}
+
+#[test_case]
+fn empty_pages_go_back() {
+    let frames_before = memory::with_memory(|_, frame_allocator| frame_allocator.used_frames());
+    let objects: Vec<_> = (0..1000).map(|i| DESCRIPTORS.alloc(Descriptor { id: i, flags: 0 }).unwrap()).collect();
+    let stats = DESCRIPTORS.stats();
+    assert!(stats.pages >= 1000 / stats.objects_per_page);
+    assert_eq!(stats.high_water_mark, 1000);
+
+    drop(objects);
+    let stats = DESCRIPTORS.stats();
+    assert!(stats.pages <= 1);
+    let frames_after = memory::with_memory(|_, frame_allocator| frame_allocator.used_frames());
+    assert!(frames_after <= frames_before + 1);
+}
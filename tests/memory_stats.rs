@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use alloc::string::ToString;
+use bootloader::{BootInfo, entry_point};
+use floof::{allocator, hlt_loop, memory, serial_println};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn stats_add_up() {
+    let stats = memory::stats();
+    serial_println!("{}", stats);
+    assert!(stats.usable_ram <= stats.total_ram);
+    assert!(stats.kernel_image > 0);
+    assert!(stats.page_tables >= 4 * 4096);
+    assert!(stats.heap_size >= allocator::HEAP_SIZE as u64);
+    assert!(stats.free_frames as u64 * 4096 <= stats.usable_ram);
+}
+
+#[test_case]
+fn free_frames_follow_allocations() {
+    let before = memory::stats().free_frames;
+    let frame = memory::with_memory(|_, frames| frames.allocate_frame()).unwrap();
+    assert_eq!(memory::stats().free_frames, before - 1);
+    memory::with_memory(|_, frames| unsafe { frames.deallocate_frame(frame) });
+    assert_eq!(memory::stats().free_frames, before);
+}
+
+#[test_case]
+fn memory_map_lists_every_region() {
+    let table = memory::memory_map().to_string();
+    serial_println!("{}", table);
+    assert!(table.lines().count() > 1);
+    assert!(table.contains("Usable"));
+    assert!(table.contains("Kernel"));
+}
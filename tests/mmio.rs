@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, mmio::{self, CacheMode}}};
+use x86_64::{PhysAddr, structures::paging::{PageTableFlags, Translate, mapper::TranslateResult}};
+
+const VGA: u64 = 0xb8000;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn uncacheable_mapping() {
+    let vga = unsafe { mmio::map_mmio(PhysAddr::new(VGA), 80 * 25 * 2, CacheMode::Uncacheable) }.unwrap();
+    let flags = memory::with_memory(|mapper, _| match mapper.translate(vga.virt_addr()) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("mmio region not mapped"),
+    });
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+
+    // the last cell of the screen, seen through the physical memory mapping too
+    let last = 80 * 25 * 2 - 2;
+    vga.write::<u16>(last, 0x0f43);
+    let through_offset: *const u16 = memory::phys_to_virt(PhysAddr::new(VGA + last as u64)).as_ptr();
+    assert_eq!(unsafe { through_offset.read_volatile() }, 0x0f43);
+    assert_eq!(vga.register::<u16>(last).read(), 0x0f43);
+}
+
+#[test_case]
+fn unaligned_write_combining_mapping() {
+    let phys = PhysAddr::new(VGA + 0x10);
+    let vga = unsafe { mmio::map_mmio(phys, 16, CacheMode::WriteCombining) }.unwrap();
+    assert_eq!(vga.virt_addr().as_u64() % 4096, 0x10);
+    let flags = memory::with_memory(|mapper, _| match mapper.translate(vga.virt_addr()) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("mmio region not mapped"),
+    });
+    // QEMU has a PAT, so the PAT bit of the 4 KiB entry selects write-combining
+    assert!(mmio::pat_supported());
+    assert_eq!(flags & CacheMode::WriteCombining.flags(), CacheMode::WriteCombining.flags());
+
+    let register = vga.register::<u32>(4);
+    register.write(0x0f41_0f42);
+    register.update(|value| value | 0x0100);
+    assert_eq!(vga.read::<u32>(4), 0x0f41_0f42 | 0x0100);
+
+    // the PAT bit must not keep the page from being unmapped
+    let virt = vga.virt_addr();
+    drop(vga);
+    let unmapped = memory::with_memory(|mapper, _| matches!(mapper.translate(virt), TranslateResult::NotMapped));
+    assert!(unmapped);
+}
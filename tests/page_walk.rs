@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use alloc::boxed::Box;
+use bootloader::{BootInfo, entry_point};
+use floof::{allocator, hlt_loop, memory::{self, vmm, walk}};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn nothing_is_mapped_at_zero() {
+    let mapping = memory::with_memory(|mapper, _| walk::mapping_of(mapper, VirtAddr::zero()));
+    assert_eq!(mapping, None);
+}
+
+#[test_case]
+fn heap_is_writable_data() {
+    let value = Box::new(67u64);
+    let addr = VirtAddr::from_ptr(&*value);
+    let mapping = memory::with_memory(|mapper, _| walk::mapping_of(mapper, addr)).unwrap();
+    assert_eq!(mapping.flags, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+}
+
+#[test_case]
+fn ranges_are_sorted_and_merged() {
+    memory::with_memory(|mapper, _| {
+        let mut mappings = walk::mappings(mapper);
+        let mut previous = mappings.next().unwrap();
+        for mapping in mappings {
+            assert!(mapping.start > previous.last());
+            // touching ranges with the same flags would have been merged
+            let touching = mapping.start.as_u64() == previous.last().as_u64() + 1;
+            assert!(!touching || mapping.flags != previous.flags);
+            previous = mapping;
+        }
+    });
+}
+
+#[test_case]
+fn allocations_show_up_as_one_range() {
+    let start = vmm::allocate("walk", 8 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let mapping = memory::with_memory(|mapper, _| walk::mapping_of(mapper, start)).unwrap();
+    assert!(mapping.contains(start + 8 * 4096u64 - 1));
+    unsafe { vmm::free(start).unwrap() };
+}
+
+#[test_case]
+fn dump_prints_without_panicking() {
+    walk::dump();
+}
@@ -5,7 +5,8 @@
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use core::panic::PanicInfo;
-use floof::{QemuExitCode, exit_qemu, gdt::{self, DOUBLE_FAULT_IST_INDEX}, serial_print, serial_println};
+use bootloader::{BootInfo, entry_point};
+use floof::{QemuExitCode, exit_qemu, gdt::{self, DOUBLE_FAULT_IST_INDEX}, memory, serial_print, serial_println};
 use volatile::Volatile;
 
 lazy_static! {
@@ -28,9 +29,11 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
+    // the double fault stack comes from the vmm
+    unsafe { memory::init_global(boot_info) };
     gdt::init();
     init_test_idt();
     stack_overflow();
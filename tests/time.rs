@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, time::{self, TimerSource}};
+use x86_64::instructions::hlt;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+#[test_case]
+fn runs_at_the_default_frequency() {
+    // QEMU emulates the APICs, so the local APIC timer is used
+    assert_eq!(time::source(), TimerSource::LocalApic);
+    let frequency = time::frequency();
+    assert!(frequency.abs_diff(time::DEFAULT_FREQUENCY) <= time::DEFAULT_FREQUENCY / 100, "ticks at {} Hz", frequency);
+}
+
+#[test_case]
+fn ticks_are_counted() {
+    let before = time::ticks();
+    for _ in 0..5 {
+        hlt();
+    }
+    assert!(time::ticks() > before);
+}
+
+#[test_case]
+fn uptime_follows_the_ticks() {
+    let before = time::uptime();
+    for _ in 0..5 {
+        hlt();
+    }
+    let uptime = time::uptime();
+    assert!(uptime > before);
+    // nothing changed the frequency yet, so every tick added the same period
+    let expected = time::ticks() * 1_000_000_000 / u64::from(time::frequency());
+    let nanos = uptime.as_nanos() as u64;
+    assert!(nanos.abs_diff(expected) <= expected / 100, "{} ns after {} ticks", nanos, time::ticks());
+}
+
+#[test_case]
+fn frequency_can_be_changed() {
+    let uptime = time::uptime();
+    let frequency = time::set_frequency(100);
+    assert!(frequency.abs_diff(100) <= 1, "ticks at {} Hz", frequency);
+    assert_eq!(time::frequency(), frequency);
+    let ticks = time::ticks();
+    hlt();
+    assert!(time::ticks() > ticks);
+    assert!(time::uptime() > uptime);
+    time::set_frequency(time::DEFAULT_FREQUENCY);
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, vmm::{self, Backing, VmmError}}};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{Page, PageTableFlags, Size4KiB, Translate, mapper::TranslateResult}};
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_memory(|_, frames| frames.free_frames())
+}
+
+fn flags_of(addr: VirtAddr) -> Option<PageTableFlags> {
+    memory::with_memory(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    })
+}
+
+#[test_case]
+fn regions_never_overlap() {
+    let a = vmm::reserve("a", 3 * 4096).unwrap();
+    let b = vmm::reserve("b", 4096).unwrap();
+    assert!(b >= a + 3 * 4096u64 || a >= b + 4096u64);
+    assert!(matches!(vmm::map(a + 4096u64, 8 * 4096, PageTableFlags::WRITABLE), Err(VmmError::NotReserved)));
+    unsafe {
+        vmm::free(a).unwrap();
+        vmm::free(b).unwrap();
+    }
+}
+
+#[test_case]
+fn unmapping_frees_frames() {
+    let before = free_frames();
+    let start = vmm::allocate("scratch", 16 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let ptr: *mut u64 = (start + 15 * 4096u64).as_mut_ptr();
+    unsafe { ptr.write_volatile(67) };
+    assert!(free_frames() <= before - 16);
+
+    let region = unsafe { vmm::free(start) }.unwrap();
+    assert_eq!(region.backing, Backing::Frames);
+    assert!(flags_of(start).is_none());
+    assert!(vmm::region_of(start).is_none());
+    // page tables the mapping needed stay around
+    assert!(free_frames() >= before - 3);
+}
+
+#[test_case]
+fn protect_changes_flags() {
+    let start = vmm::allocate("protected", 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+    assert!(flags_of(start).unwrap().contains(PageTableFlags::WRITABLE));
+
+    vmm::protect(start, 4096, PageTableFlags::empty()).unwrap();
+    assert!(!flags_of(start).unwrap().contains(PageTableFlags::WRITABLE));
+    assert!(flags_of(start + 4096u64).unwrap().contains(PageTableFlags::WRITABLE));
+    unsafe { vmm::free(start).unwrap() };
+}
+
+#[test_case]
+fn demand_regions_are_protected_whole() {
+    let start = vmm::allocate_lazy("lazy protected", 4 * 4096, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(vmm::protect(start, 4096, PageTableFlags::empty()), Err(VmmError::PartialDemand));
+    vmm::protect(start, 4 * 4096, PageTableFlags::empty()).unwrap();
+    let ptr: *const u64 = (start + 4096u64).as_ptr();
+    unsafe { ptr.read_volatile() };
+    assert!(!flags_of(start + 4096u64).unwrap().contains(PageTableFlags::WRITABLE));
+    unsafe { vmm::free(start).unwrap() };
+}
+
+#[test_case]
+fn physical_memory_is_shared() {
+    let vga = PhysAddr::new(0xb8000);
+    let start = unsafe { vmm::map_physical("vga", vga, 4096, PageTableFlags::WRITABLE) }.unwrap();
+    let through_offset: *const u16 = memory::phys_to_virt(vga).as_ptr();
+    let through_vmm: *const u16 = start.as_ptr();
+    unsafe { assert_eq!(through_vmm.read_volatile(), through_offset.read_volatile()) };
+
+    let before = free_frames();
+    unsafe { vmm::free(start).unwrap() };
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn lazy_regions_map_on_touch() {
+    let start = vmm::allocate_lazy("lazy", 64 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let page = Page::<Size4KiB>::containing_address(start) + 10;
+    assert!(flags_of(page.start_address()).is_none());
+
+    let ptr: *mut u8 = page.start_address().as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(1);
+    }
+    assert!(flags_of(page.start_address()).is_some());
+    unsafe { vmm::free(start).unwrap() };
+    assert!(flags_of(page.start_address()).is_none());
+}
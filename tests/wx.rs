@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(floof::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[allow(unused_imports)]
+use core::panic::PanicInfo;
+use bootloader::{BootInfo, entry_point};
+use floof::{hlt_loop, memory::{self, vmm, wx}};
+use x86_64::{PhysAddr, VirtAddr, registers::model_specific::{Efer, EferFlags}, structures::paging::{PageTableFlags, Translate, mapper::TranslateResult}};
+
+static mut DATA: u64 = 0;
+
+entry_point!(main);
+fn main(bootinfo: &'static BootInfo) -> ! {
+    floof::init(bootinfo);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    floof::test_panic_handler(info)
+}
+
+fn flags(addr: VirtAddr) -> PageTableFlags {
+    memory::with_memory(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:?} is not mapped", addr),
+    })
+}
+
+fn is_data(addr: VirtAddr) -> bool {
+    let flags = flags(addr);
+    flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+}
+
+#[test_case]
+fn nxe_is_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+    assert!(wx::nx_enabled());
+}
+
+#[test_case]
+fn code_is_read_only_and_executable() {
+    let code = VirtAddr::new(main as *const () as u64);
+    assert!(wx::is_kernel_code(code));
+    let flags = flags(code);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn data_and_stack_are_not_executable() {
+    let local = 0u64;
+    assert!(is_data(VirtAddr::from_ptr(&raw const DATA)));
+    assert!(is_data(VirtAddr::from_ptr(&local)));
+    assert!(is_data(memory::phys_to_virt(PhysAddr::new(0))));
+}
+
+#[test_case]
+fn new_mappings_are_not_executable() {
+    let start = vmm::allocate("wx", 4096, PageTableFlags::WRITABLE).unwrap();
+    assert!(is_data(start));
+    unsafe { vmm::free(start).unwrap() };
+}